@@ -0,0 +1,26 @@
+use std::{fmt::Write, path::Path};
+
+use syn_file_expand::CfgPredicate;
+
+/// Format predicates, each followed by indented list of `file:line kind` locations
+pub(crate) fn print_cfgs(root_file: &Path, cfgs: &[CfgPredicate]) -> String {
+    let root_dir = root_file.parent().unwrap_or_else(|| Path::new(""));
+    let mut buf = String::new();
+    for cfg in cfgs {
+        let _ = writeln!(buf, "{} ({})", cfg.predicate, cfg.locations.len());
+        for location in &cfg.locations {
+            let file = match &location.file {
+                Some(x) => root_dir.join(x),
+                None => root_file.to_owned(),
+            };
+            let _ = write!(buf, "    {}:{} {}", file.display(), location.line, location.kind);
+            if !location.module.segments.is_empty() {
+                let module: Vec<String> =
+                    location.module.segments.iter().map(|x| x.ident.to_string()).collect();
+                let _ = write!(buf, " in {}", module.join("::"));
+            }
+            buf.push('\n');
+        }
+    }
+    buf
+}
@@ -0,0 +1,56 @@
+use std::{io::Write, path::Path};
+
+use syn_file_expand::LoadedFiles;
+
+/// Escape a path for use in a Makefile rule
+fn escape(path: &Path) -> String {
+    let mut buf = String::new();
+    for c in path.to_string_lossy().chars() {
+        match c {
+            ' ' | '#' | ':' | '\\' => {
+                buf.push('\\');
+                buf.push(c);
+            }
+            '$' => buf.push_str("$$"),
+            _ => buf.push(c),
+        }
+    }
+    buf
+}
+
+/// Write `target: dependencies...` rule, followed by an empty rule for each non-root dependency
+/// (like `gcc -MP` does), so that deleting a module file does not break the build.
+///
+/// Candidate files that were not found are not included, as Make would try to build them.
+pub(crate) fn write(depfile: &Path, target: &Path, files: &LoadedFiles) -> std::io::Result<()> {
+    let f = std::fs::File::create(depfile)?;
+    let mut f = std::io::BufWriter::new(f);
+    write!(f, "{}:", escape(target))?;
+    for x in &files.read {
+        write!(f, " \\\n  {}", escape(x))?;
+    }
+    writeln!(f)?;
+    for x in files.read.iter().skip(1) {
+        writeln!(f, "\n{}:", escape(x))?;
+    }
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::escape;
+    use std::path::Path;
+
+    #[test]
+    fn plain() {
+        assert_eq!(escape(Path::new("src/lib.rs")), "src/lib.rs");
+    }
+
+    #[test]
+    fn special_characters() {
+        assert_eq!(escape(Path::new("my src/lib.rs")), "my\\ src/lib.rs");
+        assert_eq!(escape(Path::new("src/#1.rs")), "src/\\#1.rs");
+        assert_eq!(escape(Path::new("src/$x.rs")), "src/$$x.rs");
+        assert_eq!(escape(Path::new("src/a:b.rs")), "src/a\\:b.rs");
+    }
+}
@@ -5,14 +5,8 @@ pub(crate) fn get_env_name(input: proc_macro2::TokenStream) -> String {
     let mut need_underscore = false;
     for t in input {
         let to_insert: String = match t {
-            proc_macro2::TokenTree::Group(g) => {
-                let ret = get_env_name(g.stream());
-                ret
-            }
-            proc_macro2::TokenTree::Ident(x) => {
-                let x = x.to_string().to_ascii_uppercase();
-                x
-            }
+            proc_macro2::TokenTree::Group(g) => get_env_name(g.stream()),
+            proc_macro2::TokenTree::Ident(x) => x.to_string().to_ascii_uppercase(),
             proc_macro2::TokenTree::Literal(x) => {
                 if let Ok(l) = syn::parse2::<syn::Lit>(x.clone().into_token_stream()) {
                     match l {
@@ -58,13 +52,10 @@ pub(crate) fn get_cli_name(input: proc_macro2::TokenStream) -> String {
                     proc_macro2::Delimiter::Parenthesis => format!("({})", ret),
                     proc_macro2::Delimiter::Brace => format!("{{{}}}", ret),
                     proc_macro2::Delimiter::Bracket => format!("[{}]", ret),
-                    proc_macro2::Delimiter::None => format!("{}", ret),
+                    proc_macro2::Delimiter::None => ret,
                 }
             }
-            proc_macro2::TokenTree::Ident(x) => {
-                let x = x.to_string();
-                x
-            }
+            proc_macro2::TokenTree::Ident(x) => x.to_string(),
             proc_macro2::TokenTree::Literal(x) => {
                 if let Ok(l) = syn::parse2::<syn::Lit>(x.clone().into_token_stream()) {
                     match l {
@@ -1,6 +1,10 @@
-use std::{collections::HashSet, path::PathBuf};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
 
 use quote::ToTokens;
+use syn_file_expand::LoadedFiles;
 
 /// Use `syn-file-expand-cli -fTp src/lib.rs` as a starting point.
 /// 
@@ -53,6 +57,35 @@ struct Opts {
     #[options(short = 'd')]
     debug_cfg: bool,
 
+    /** Also evaluate `cfg`s on all other items, fields, statements, match arms, etc.,
+                   removing what is disabled and unwrapping `cfg_attr`s, like the compiler does.*/
+    #[options(no_short)]
+    strip_cfg: bool,
+
+    /** Replace all `cfg_attr`s with attributes they resolve to, keeping `cfg`s intact.
+                   Implied by `--strip-cfg`.*/
+    #[options(no_short)]
+    normalize_cfg_attr: bool,
+
+    /** Splice source files as text instead of printing parsed code, preserving comments and formatting.
+                   Cannot be combined with `-f`, `-l`, `-D`, `-p`, `--strip-cfg`, `--normalize-cfg-attr` or `--bundle`.*/
+    #[options(no_short)]
+    keep_formatting: bool,
+
+    /** Put a `// ---- path ----` comment at the start of each module that was loaded from a file.
+                   Cannot be combined with `--keep-formatting`.*/
+    #[options(no_short)]
+    markers: bool,
+
+    /** Treat input file as a binary crate and bundle the library crate with this root file (e.g. `mylib/src/lib.rs`)
+                   into it as `mod mylib`, rewriting paths, to get a single compilable file.*/
+    #[options(no_short, meta = "FILE")]
+    bundle: Option<PathBuf>,
+
+    /// Name the binary refers to the `--bundle`d library by. Inferred from the directory containing `src/lib.rs` by default.
+    #[options(no_short, meta = "NAME")]
+    bundle_name: Option<String>,
+
     /// Use given file for output instead of stdout
     #[options(short = 'o')]
     output: Option<PathBuf>,
@@ -60,21 +93,309 @@ struct Opts {
     /// Use `prettyplease` to format the output
     #[options(short = 'p')]
     pretty: bool,
+
+    /** Keep running and rewrite `--output` each time one of the source files
+                   (or a not yet existing candidate module file) changes.*/
+    #[options(short = 'w')]
+    watch: bool,
+
+    /// Write a Makefile-compatible list of files the output depends on. Requires `--output`.
+    #[options(no_short, meta = "FILE")]
+    depfile: Option<PathBuf>,
+
+    /// Print paths of all source files that were read instead of the expanded code (unless `--output` is also specified).
+    #[options(no_short)]
+    list_files: bool,
+
+    /// Print hierarchy of modules with their files, visibility and `cfg`s instead of the expanded code
+    #[options(no_short)]
+    tree: bool,
+
+    /** Print every distinct `cfg` predicate used in the crate (in all module variants), with usage counts
+                   and locations, instead of the expanded code.*/
+    #[options(no_short)]
+    list_cfgs: bool,
+
+    /** Print each distinct module hierarchy that can be obtained by setting `cfg`s used in module
+                   declarations, with a representative set of enabled `cfg`s, instead of the expanded code.*/
+    #[options(no_short)]
+    layouts: bool,
+
+    /** Output format: `rust` (default) for the expanded code or `json` for a machine-readable
+                   report about modules, files, skipped modules and errors instead of the code.*/
+    #[options(no_short, meta = "FORMAT")]
+    format: Option<String>,
 }
 
+mod cfgs;
+mod depfile;
 mod getcfgname;
 mod loopify;
+mod markers;
+mod tree;
 mod undoc;
+mod watch;
 
 fn main() {
-    let opts: Opts = gumdrop::parse_args_or_exit(gumdrop::ParsingStyle::AllOptions);
+    let mut opts: Opts = gumdrop::parse_args_or_exit(gumdrop::ParsingStyle::AllOptions);
+
+    if opts.watch && opts.output.is_none() {
+        eprintln!("--watch requires --output");
+        std::process::exit(1);
+    }
+    match opts.format.as_deref() {
+        None | Some("rust") | Some("json") => (),
+        Some(x) => {
+            eprintln!("Unknown output format `{}`", x);
+            std::process::exit(1);
+        }
+    }
+    if opts.bundle_name.is_some() && opts.bundle.is_none() {
+        eprintln!("--bundle-name requires --bundle");
+        std::process::exit(1);
+    }
+    if opts.depfile.is_some() && opts.output.is_none() {
+        eprintln!("--depfile requires --output");
+        std::process::exit(1);
+    }
+
+    if opts.keep_formatting
+        && (opts.full_crate_tree
+            || opts.loopify
+            || opts.undoc
+            || opts.pretty
+            || opts.strip_cfg
+            || opts.normalize_cfg_attr
+            || opts.bundle.is_some())
+    {
+        eprintln!("--keep-formatting cannot be combined with options that transform the code");
+        std::process::exit(1);
+    }
+    if opts.keep_formatting && opts.markers {
+        eprintln!("--markers cannot be combined with --keep-formatting");
+        std::process::exit(1);
+    }
+
+    let set_cfg = HashSet::<String>::from_iter(std::mem::take(&mut opts.cfg));
+    let unset_cfg = HashSet::<String>::from_iter(std::mem::take(&mut opts.unset_cfg));
+
+    if !opts.watch {
+        if let Err(code) = run(&opts, &set_cfg, &unset_cfg, &mut LoadedFiles::default()) {
+            std::process::exit(code);
+        }
+        return;
+    }
+
+    let mut files = LoadedFiles::default();
+    loop {
+        // Take modification times before expanding, so that edits made while
+        // the expansion is in progress are not missed.
+        let mut snapshot = watch::Snapshot::take(&files);
+        files = LoadedFiles::default();
+        let _ = run(&opts, &set_cfg, &unset_cfg, &mut files);
+        snapshot.extend(&files);
+        snapshot.wait_for_changes();
+    }
+}
+
+/// Expand the input file according to `opts` and write the result.
+/// Errors are reported to stderr, returned value is the process exit code to use.
+fn run(
+    opts: &Opts,
+    set_cfg: &HashSet<String>,
+    unset_cfg: &HashSet<String>,
+    files: &mut LoadedFiles,
+) -> Result<(), i32> {
+    if opts.tree {
+        let tree = match read_source(opts, &opts.input_file, set_cfg, unset_cfg, files) {
+            Ok(x) => x.modules,
+            Err(e) => {
+                eprintln!("{}", e);
+                return Err(2);
+            }
+        };
+        return write_text_output(opts, &tree::print_tree(&opts.input_file, &tree), files);
+    }
+
+    if opts.layouts {
+        let layouts = match syn_file_expand::read_module_layouts_tracked(&opts.input_file, files) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("{}", e);
+                return Err(2);
+            }
+        };
+        return write_text_output(opts, &tree::print_layouts(&opts.input_file, &layouts), files);
+    }
+
+    if opts.list_cfgs {
+        let cfgs = match syn_file_expand::read_cfg_predicates_tracked(&opts.input_file, files) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("{}", e);
+                return Err(2);
+            }
+        };
+        return write_text_output(opts, &cfgs::print_cfgs(&opts.input_file, &cfgs), files);
+    }
+
+    if opts.format.as_deref() == Some("json") {
+        let report = syn_file_expand::read_expansion_report(
+            &opts.input_file,
+            cfg_checker(opts, set_cfg, unset_cfg),
+            opts.full_crate_tree,
+        );
+        files.read.extend(report.files_read.iter().map(PathBuf::from));
+        files.missing.extend(report.files_missing.iter().map(PathBuf::from));
+        let json = serde_json::to_string_pretty(&report).expect("report is serializable");
+        write_text_output(opts, &format!("{}\n", json), files)?;
+        return if report.errors.is_empty() { Ok(()) } else { Err(2) };
+    }
+
+    if opts.keep_formatting {
+        let expansion = syn_file_expand::Expander::new()
+            .cfg_provider(cfg_checker(opts, set_cfg, unset_cfg))
+            .expand_path_to_text(&opts.input_file);
+        let expansion = match expansion {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("{}", e);
+                return Err(2);
+            }
+        };
+        *files = expansion.files;
+        return write_text_output(opts, &expansion.text, files);
+    }
+
+    let mut marked = Vec::new();
+    let source = read_source(opts, &opts.input_file, set_cfg, unset_cfg, files).and_then(|bin| {
+        marked = markers::Marked::from_tree(&opts.input_file, &bin.modules);
+        let Some(lib_path) = &opts.bundle else { return Ok(bin.file) };
+        let lib = read_source(opts, lib_path, set_cfg, unset_cfg, files)?;
+        let name = bundle_name(opts, lib_path);
+        marked.insert(0, markers::Marked::bundled_library(&name, lib_path, &lib.modules));
+        Ok(syn_file_expand::bundle(bin.file, lib.file, &name))
+    });
+    let mut source = match source {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Err(2);
+        }
+    };
+    if opts.strip_cfg {
+        let mut checker = cfg_checker(opts, set_cfg, unset_cfg);
+        if let Err(e) = syn_file_expand::strip_cfg(&mut source, |query| checker(query.cfg)) {
+            eprintln!("{}", e);
+            return Err(2);
+        }
+    } else if opts.normalize_cfg_attr {
+        let mut checker = cfg_checker(opts, set_cfg, unset_cfg);
+        if let Err(e) = syn_file_expand::normalize_cfg_attr(&mut source, |query| checker(query.cfg)) {
+            eprintln!("{}", e);
+            return Err(2);
+        }
+    }
+    if opts.loopify {
+        loopify::loopify(&mut source);
+    }
+    if opts.undoc {
+        undoc::undoc(&mut source);
+    }
+    if opts.output.is_some() || !opts.list_files {
+        let text = if opts.pretty {
+            #[cfg(feature="prettyplease")]
+            {
+                prettyplease::unparse(&source)
+            }
+            #[cfg(not(feature="prettyplease"))]
+            {
+                eprintln!("--pretty support is not enabled at compile time");
+                std::process::exit(3);
+            } 
+        } else {
+            format!("{}\n", source.into_token_stream())
+        };
+        let text = if opts.markers {
+            markers::insert_markers(&text, marked)
+        } else {
+            text
+        };
+        write_output(opts, &text)?;
+    }
+    finish(opts, files)
+}
 
-    let set_cfg = HashSet::<String>::from_iter(opts.cfg.into_iter());
-    let unset_cfg = HashSet::<String>::from_iter(opts.unset_cfg.into_iter());
+/// Write already expanded text like [`run`] does, honoring `--list-files` and `--depfile`
+fn write_text_output(opts: &Opts, text: &str, files: &LoadedFiles) -> Result<(), i32> {
+    if opts.output.is_some() || !opts.list_files {
+        write_output(opts, text)?;
+    }
+    finish(opts, files)
+}
 
+/// Load and expand a crate, recording consulted files to `files` even if it fails
+fn read_source(
+    opts: &Opts,
+    path: &Path,
+    set_cfg: &HashSet<String>,
+    unset_cfg: &HashSet<String>,
+    files: &mut LoadedFiles,
+) -> Result<syn_file_expand::Expansion, syn_file_expand::Error> {
+    syn_file_expand::Expander::new()
+        .cfg_provider(cfg_checker(opts, set_cfg, unset_cfg))
+        .multimodule(opts.full_crate_tree)
+        .filesystem(|path: &Path| {
+            let ret = std::fs::read_to_string(path);
+            match ret {
+                Ok(_) => files.read.push(path.to_owned()),
+                Err(_) => files.missing.push(path.to_owned()),
+            }
+            ret
+        })
+        .expand_path(path)
+}
+
+/// `--bundle-name` or the name of the directory containing `src/lib.rs`
+fn bundle_name(opts: &Opts, lib_path: &Path) -> String {
+    if let Some(name) = &opts.bundle_name {
+        return name.clone();
+    }
+    let src = lib_path.parent().filter(|x| x.file_name().is_some_and(|x| x == "src"));
+    match src.and_then(Path::parent).and_then(Path::file_name) {
+        Some(x) => x.to_string_lossy().into_owned(),
+        None => {
+            eprintln!("Cannot infer library name from {}, use --bundle-name", lib_path.display());
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Handle `--list-files` and `--depfile` after the output is written
+fn finish(opts: &Opts, files: &LoadedFiles) -> Result<(), i32> {
+    if opts.list_files {
+        for x in &files.read {
+            println!("{}", x.display());
+        }
+    }
+    if let (Some(depfile), Some(output)) = (&opts.depfile, &opts.output) {
+        if let Err(e) = depfile::write(depfile, output, files) {
+            eprintln!("Writing depfile failed: {}", e);
+            return Err(3);
+        }
+    }
+    Ok(())
+}
+
+/// Create `check_cfg` callback based on command line options and environment variables
+fn cfg_checker<'a>(
+    opts: &'a Opts,
+    set_cfg: &'a HashSet<String>,
+    unset_cfg: &'a HashSet<String>,
+) -> impl FnMut(syn::Meta) -> Result<bool, syn_file_expand::UserError> + 'a {
     let debug_env = std::env::var("SYN_FILE_EXPAND_DEBUGVARS") == Ok("1".to_owned());
     let default = std::env::var("SYN_FILE_EXPAND_DEFAULTTRUE") == Ok("1".to_owned());
-    let mut source = match syn_file_expand::read_full_crate_source_code_ex(&opts.input_file, |cfg| {
+    move |cfg| {
         let envname = format!(
             "SYN_FILE_EXPAND_{}",
             getcfgname::get_env_name(cfg.clone().to_token_stream())
@@ -89,62 +410,22 @@ fn main() {
         Ok(if opts.cfg_true_by_default || set_cfg.contains(&cliname) {
             !unset_cfg.contains(&cliname)
         } else if let Ok(x) = std::env::var(&envname) {
-            if x == "1" {
-                true
-            } else {
-                false
-            }
+            x == "1"
         } else {
             default
         })
-    }, opts.full_crate_tree) {
-        Ok(x) => x,
-        Err(e) => {
-            eprintln!("{}", e);
-            std::process::exit(2)
-        }
-    };
-    if opts.loopify {
-        loopify::loopify(&mut source);
     }
-    if opts.undoc {
-        undoc::undoc(&mut source);
-    }
-    if let Some(output) = opts.output {
-        match (|| -> std::io::Result<()> {
-            use std::io::Write;
-            let f = std::fs::File::create(output)?;
-            let mut f = std::io::BufWriter::with_capacity(128*1024, f);
-            if opts.pretty {
-                #[cfg(feature="prettyplease")]
-                writeln!(f, "{}", prettyplease::unparse(&source))?;
-                #[cfg(not(feature="prettyplease"))]
-                {
-                    eprintln!("--pretty support is not enabled at compile time");
-                    std::process::exit(3);
-                } 
-            } else {
-                writeln!(f, "{}", source.into_token_stream())?;
-            }
-            Ok(())
-        })() {
-            Ok(()) => (),
-            Err(e) => {
-                eprintln!("Output failed: {}", e);
-                std::process::exit(3)
-            }
+}
+
+/// Write text to `--output` file or stdout
+fn write_output(opts: &Opts, text: &str) -> Result<(), i32> {
+    if let Some(output) = &opts.output {
+        if let Err(e) = std::fs::write(output, text) {
+            eprintln!("Output failed: {}", e);
+            return Err(3);
         }
     } else {
-        if opts.pretty {
-            #[cfg(feature="prettyplease")]
-            println!("{}", prettyplease::unparse(&source));
-            #[cfg(not(feature="prettyplease"))]
-            {
-                eprintln!("--pretty support is not enabled at compile time");
-                std::process::exit(3);
-            } 
-        } else {
-            println!("{}", source.into_token_stream());
-        }
+        print!("{}", text);
     }
+    Ok(())
 }
@@ -0,0 +1,185 @@
+use std::path::{Path, PathBuf};
+
+use syn::visit::Visit;
+use syn_file_expand::ModuleTree;
+
+/// Module that gets a `// ---- path ----` marker if it was loaded from a file
+#[derive(Debug)]
+pub(crate) struct Marked {
+    name: String,
+    /// Path of the file, as it was read
+    path: Option<PathBuf>,
+    children: Vec<Marked>,
+}
+
+impl Marked {
+    /// Modules of `tree` of the crate with the given root file
+    pub(crate) fn from_tree(root: &Path, tree: &[ModuleTree]) -> Vec<Marked> {
+        let dir = root.parent().unwrap_or(Path::new(""));
+        tree.iter().map(|x| Marked::from_node(dir, x)).collect()
+    }
+
+    fn from_node(dir: &Path, node: &ModuleTree) -> Marked {
+        Marked {
+            name: node.name.to_string(),
+            path: node.file.as_ref().map(|x| dir.join(x)),
+            children: node.children.iter().map(|x| Marked::from_node(dir, x)).collect(),
+        }
+    }
+
+    /// Library module inserted by `--bundle`, with the library's own modules inside
+    pub(crate) fn bundled_library(name: &str, root: &Path, tree: &[ModuleTree]) -> Marked {
+        Marked {
+            name: name.replace('-', "_"),
+            path: Some(root.to_owned()),
+            children: Marked::from_tree(root, tree),
+        }
+    }
+}
+
+/// Put a `// ---- path ----` comment after the opening brace of each module of the printed code
+/// that was loaded from a file. Modules are matched to `modules` by name, in order.
+/// If the brace ends its line (`prettyplease` output) or the module is empty, the comment goes to the next line
+/// with the indentation of the module's content, otherwise (token output) it is put on a separate line.
+pub(crate) fn insert_markers(text: &str, modules: Vec<Marked>) -> String {
+    let Ok(file) = syn::parse_file(text) else {
+        return text.to_owned();
+    };
+    let mut finder = Finder {
+        stack: vec![modules],
+        found: Vec::new(),
+    };
+    finder.visit_file(&file);
+
+    let mut ret = text.to_owned();
+    for (brace, path) in finder.found.into_iter().rev() {
+        let Some(offset) = offset(text, brace).map(|x| x + 1) else { continue };
+        let comment = format!("// ---- {} ----", path.display());
+        let line_end = text[offset..].find('\n').map_or(text.len(), |x| offset + x);
+        let line_start = text[..offset].rfind('\n').map_or(0, |x| x + 1);
+        let line = &text[line_start..];
+        let indent = &line[..line.len() - line.trim_start().len()];
+        let spaces = text[offset..].len() - text[offset..].trim_start_matches(' ').len();
+        if text[offset..line_end].trim().is_empty() {
+            ret.insert_str(offset, &format!("\n{indent}    {comment}"));
+        } else if text[offset + spaces..].starts_with('}') {
+            // Empty module
+            ret.replace_range(offset..offset + spaces, &format!("\n{indent}    {comment}\n{indent}"));
+        } else {
+            ret.replace_range(offset..offset + spaces, &format!("\n{comment}\n"));
+        }
+    }
+    ret
+}
+
+/// Records positions of opening braces of modules that have a file
+struct Finder {
+    /// Not yet matched modules of each enclosing module
+    stack: Vec<Vec<Marked>>,
+    found: Vec<(proc_macro2::LineColumn, PathBuf)>,
+}
+
+impl<'ast> Visit<'ast> for Finder {
+    fn visit_item_mod(&mut self, i: &'ast syn::ItemMod) {
+        let Some((brace, _)) = &i.content else { return };
+        let siblings = self.stack.last_mut().expect("stack is not empty");
+        let children = match siblings.iter().position(|x| i.ident == x.name) {
+            Some(index) => {
+                let node = siblings.remove(index);
+                if let Some(path) = node.path {
+                    self.found.push((brace.span.open().start(), path));
+                }
+                node.children
+            }
+            None => Vec::new(),
+        };
+        self.stack.push(children);
+        syn::visit::visit_item_mod(self, i);
+        self.stack.pop();
+    }
+}
+
+/// Byte offset of the position in the text, `None` if it is out of range
+fn offset(text: &str, position: proc_macro2::LineColumn) -> Option<usize> {
+    let line_start = match position.line.checked_sub(2) {
+        None => 0,
+        Some(x) => text.match_indices('\n').nth(x)?.0 + 1,
+    };
+    let column = text[line_start..].char_indices().nth(position.column)?.0;
+    Some(line_start + column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(name: &str, path: Option<&str>, children: Vec<Marked>) -> Marked {
+        Marked {
+            name: name.to_owned(),
+            path: path.map(PathBuf::from),
+            children,
+        }
+    }
+
+    fn sample() -> Vec<Marked> {
+        vec![
+            module("a", Some("src/a.rs"), vec![module("b", Some("src/a/b.rs"), vec![])]),
+            module("inline", None, vec![module("c", Some("src/inline/c.rs"), vec![])]),
+            module("a", Some("src/a2.rs"), vec![]),
+        ]
+    }
+
+    #[test]
+    fn token_output() {
+        let text = concat!(
+            "mod a { mod b { struct B ; } } mod inline { mod c { } } ",
+            "# [cfg (x)] mod a { const S : & str = \"mod a { struct A ; }\" ; }\n",
+        );
+        let expected = concat!(
+            "mod a {\n// ---- src/a.rs ----\nmod b {\n// ---- src/a/b.rs ----\nstruct B ; } } ",
+            "mod inline { mod c {\n    // ---- src/inline/c.rs ----\n} } ",
+            "# [cfg (x)] mod a {\n// ---- src/a2.rs ----\nconst S : & str = \"mod a { struct A ; }\" ; }\n",
+        );
+        assert_eq!(insert_markers(text, sample()), expected);
+    }
+
+    #[test]
+    fn pretty_output() {
+        let text = concat!(
+            "mod a {\n",
+            "    mod b {\n",
+            "        struct B;\n",
+            "    }\n",
+            "}\n",
+            "mod inline {\n",
+            "    mod c {}\n",
+            "}\n",
+            "#[cfg(x)]\n",
+            "mod a {\n",
+            "    const S: &str = \"mod a {\n",
+            "    \";\n",
+            "}\n",
+        );
+        let expected = concat!(
+            "mod a {\n",
+            "    // ---- src/a.rs ----\n",
+            "    mod b {\n",
+            "        // ---- src/a/b.rs ----\n",
+            "        struct B;\n",
+            "    }\n",
+            "}\n",
+            "mod inline {\n",
+            "    mod c {\n",
+            "        // ---- src/inline/c.rs ----\n",
+            "    }\n",
+            "}\n",
+            "#[cfg(x)]\n",
+            "mod a {\n",
+            "    // ---- src/a2.rs ----\n",
+            "    const S: &str = \"mod a {\n",
+            "    \";\n",
+            "}\n",
+        );
+        assert_eq!(insert_markers(text, sample()), expected);
+    }
+}
@@ -0,0 +1,69 @@
+use std::{fmt::Write, path::Path};
+
+use quote::ToTokens;
+use syn_file_expand::{ModuleLayouts, ModuleTree};
+
+/// Token stream's `Display` with less spaces, e.g. `not(any(a, b))` instead of `not (any (a , b))`
+fn tidy_tokens(x: &impl ToTokens) -> String {
+    x.to_token_stream()
+        .to_string()
+        .replace(" (", "(")
+        .replace("( ", "(")
+        .replace(" )", ")")
+        .replace(" ,", ",")
+}
+
+fn print_node(buf: &mut String, prefix: &str, last: bool, node: &ModuleTree) {
+    let (branch, continuation) = if last {
+        ("└── ", "    ")
+    } else {
+        ("├── ", "│   ")
+    };
+    let vis = tidy_tokens(&node.vis);
+    let _ = write!(buf, "{}{}", prefix, branch);
+    if !vis.is_empty() {
+        let _ = write!(buf, "{} ", vis);
+    }
+    let _ = write!(buf, "mod {}", node.name);
+    for cfg in &node.cfg {
+        let _ = write!(buf, " #[cfg({})]", tidy_tokens(cfg));
+    }
+    if let Some(file) = &node.file {
+        let _ = write!(buf, " ({})", file.display());
+    }
+    buf.push('\n');
+    print_children(buf, &format!("{}{}", prefix, continuation), &node.children);
+}
+
+fn print_children(buf: &mut String, prefix: &str, children: &[ModuleTree]) {
+    for (i, node) in children.iter().enumerate() {
+        print_node(buf, prefix, i + 1 == children.len(), node);
+    }
+}
+
+/// Format module tree similar to `cargo modules`
+pub(crate) fn print_tree(root_file: &Path, tree: &[ModuleTree]) -> String {
+    let mut buf = format!("crate ({})\n", root_file.display());
+    print_children(&mut buf, "", tree);
+    buf
+}
+
+/// Format each distinct module tree, preceded by a comment with its representative configuration
+pub(crate) fn print_layouts(root_file: &Path, layouts: &ModuleLayouts) -> String {
+    let mut buf = String::new();
+    for layout in &layouts.layouts {
+        let _ = writeln!(
+            buf,
+            "# enabled: [{}] ({} of {} configurations)",
+            layout.enabled.join(", "),
+            layout.configurations,
+            1usize << layouts.atoms.len(),
+        );
+        buf.push_str(&print_tree(root_file, &layout.modules));
+        buf.push('\n');
+    }
+    for x in &layouts.invalid {
+        let _ = writeln!(buf, "# invalid: [{}]: {}", x.enabled.join(", "), x.error);
+    }
+    buf
+}
@@ -0,0 +1,49 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use syn_file_expand::LoadedFiles;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(300);
+
+/// Modification times of files an expansion depends on.
+/// Missing candidate files are recorded as `None`, so that their creation is also noticed.
+pub(crate) struct Snapshot(Vec<(PathBuf, Option<SystemTime>)>);
+
+fn mtime(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl Snapshot {
+    pub(crate) fn take(files: &LoadedFiles) -> Snapshot {
+        let mut s = Snapshot(Vec::new());
+        s.extend(files);
+        s
+    }
+
+    /// Add files that are not yet in the snapshot, using their current modification times.
+    pub(crate) fn extend(&mut self, files: &LoadedFiles) {
+        for path in files.read.iter().chain(files.missing.iter()) {
+            if !self.0.iter().any(|(p, _)| p == path) {
+                self.0.push((path.clone(), mtime(path)));
+            }
+        }
+    }
+
+    fn changed(&self) -> bool {
+        self.0.iter().any(|(path, t)| mtime(path) != *t)
+    }
+
+    /// Block until any of the files gets created, deleted or modified.
+    pub(crate) fn wait_for_changes(&self) {
+        loop {
+            std::thread::sleep(POLL_INTERVAL);
+            if self.changed() {
+                // Let the editor finish writing before reading the files again.
+                std::thread::sleep(POLL_INTERVAL);
+                return;
+            }
+        }
+    }
+}
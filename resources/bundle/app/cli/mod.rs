@@ -0,0 +1,5 @@
+use lib::add;
+
+pub fn run() -> u32 {
+    add(mylib::quad!(3), format!("{}", mylib::util::add(1, 1)).len() as u32)
+}
@@ -0,0 +1,12 @@
+#[macro_use]
+extern crate mylib;
+extern crate mylib as lib;
+
+mod cli;
+
+use mylib::util::quadruple;
+
+fn main() {
+    let w = ::mylib::Wrapper(quad!(1));
+    println!("{} {} {}", w.0, quadruple(2), cli::run());
+}
@@ -0,0 +1,17 @@
+#![no_std]
+#![allow(clippy::all)]
+
+pub mod util;
+
+pub(crate) fn double(x: u32) -> u32 {
+    crate::util::add(x, x)
+}
+
+pub use crate::util::{add, Wrapper};
+
+#[macro_export]
+macro_rules! quad {
+    ($x:expr) => {
+        $crate::util::add($crate::double($x), $crate::double($x))
+    };
+}
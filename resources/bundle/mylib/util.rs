@@ -0,0 +1,11 @@
+use crate::double;
+
+pub struct Wrapper(pub u32);
+
+pub fn add(a: u32, b: u32) -> u32 {
+    a + b
+}
+
+pub fn quadruple(x: u32) -> u32 {
+    double(crate::quad!(x) / 4) * 2
+}
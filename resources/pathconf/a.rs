@@ -0,0 +1,34 @@
+// a_sibling.rs, relative to the directory of this file
+#[path = "a_sibling.rs"]
+mod p;
+
+// a/n.rs
+mod n;
+
+mod inline {
+    // a/inline/other.rs
+    #[path = "other.rs"]
+    mod inner;
+
+    // a/inline/nat.rs
+    mod nat;
+}
+
+// `#[path]` of an inline module is relative to the directory of this file as well
+#[path = "a_renamed"]
+mod inline_path {
+    // a_renamed/q.rs
+    mod q;
+}
+
+fn f() {
+    // a_block.rs, modules in blocks need `#[path]`, relative to the directory of this file
+    #[path = "a_block.rs"]
+    mod block;
+
+    mod scoped {
+        // scoped/x.rs, without the file stem
+        #[path = "x.rs"]
+        mod x;
+    }
+}
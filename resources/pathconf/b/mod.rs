@@ -0,0 +1,9 @@
+// b/b_sibling.rs
+#[path = "b_sibling.rs"]
+mod p;
+
+mod inline {
+    // b/inline/other.rs
+    #[path = "other.rs"]
+    mod inner;
+}
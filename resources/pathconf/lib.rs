@@ -0,0 +1,33 @@
+// Expected module files are in comments, see
+// https://doc.rust-lang.org/reference/items/modules.html#the-path-attribute
+
+// a.rs, non-mod-rs file
+mod a;
+
+// b/mod.rs
+mod b;
+
+// sub/e.rs, files loaded by `#[path]` behave like mod-rs files
+#[path = "sub/e.rs"]
+mod e;
+
+mod inline {
+    // inline/c.rs
+    mod c;
+
+    // inline/d.rs
+    #[path = "d.rs"]
+    mod d;
+}
+
+#[path = "renamed"]
+mod inline_path {
+    // renamed/g.rs
+    mod g;
+}
+
+const _: () = {
+    // sub/h.rs
+    #[path = "sub/h.rs"]
+    mod h;
+};
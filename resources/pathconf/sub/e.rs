@@ -0,0 +1,2 @@
+// sub/f.rs
+mod f;
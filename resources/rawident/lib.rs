@@ -0,0 +1,5 @@
+mod r#type;
+mod r#async;
+mod r#match {
+    mod r#fn;
+}
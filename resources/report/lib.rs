@@ -0,0 +1,16 @@
+#[cfg(feature = "off")]
+mod disabled_file;
+
+#[cfg(feature = "off")]
+mod disabled_inline {
+    mod nested;
+}
+
+#[cfg(feature = "on")]
+mod enabled_inline {
+    mod nested;
+}
+
+mod plain_inline {
+    struct S;
+}
@@ -1,4 +1,3 @@
-use proc_macro2;
 use proc_macro2::TokenStream;
 use quote::ToTokens;
 use syn::MacroDelimiter;
@@ -89,3 +88,34 @@ pub(crate) fn read_and_process_attributes(
     }
     Ok(())
 }
+
+/// Whether the attribute is `#[path]` or a `#[cfg_attr]` that contains `path`
+pub(crate) fn is_path_attribute(attr: &syn::Attribute) -> bool {
+    if attr.path().is_ident("cfg_attr") {
+        match attr.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated) {
+            Ok(nested) => nested.iter().skip(1).any(|x| x.path().is_ident("path")),
+            Err(_) => false,
+        }
+    } else {
+        attr.path().is_ident("path")
+    }
+}
+
+/// Like [`read_and_process_attributes`], but for inner attributes of a loaded module file
+/// or attributes of an inline module.
+/// `cfg_attr`s that do not contain `path` are kept as is, whatever number of parameters they have.
+pub(crate) fn read_inner_attributes(
+    input_attrs: Vec<syn::Attribute>,
+    path_attrs: &mut Vec<(PathBuf, Option<TokenStream>)>,
+    attrs: &mut Vec<syn::Attribute>,
+    cfg_attrs: &mut Vec<TokenStream>,
+) -> Result<(), AttrParseError> {
+    for attr in input_attrs {
+        if attr.path().is_ident("cfg") || is_path_attribute(&attr) {
+            read_and_process_attributes(std::slice::from_ref(&attr), path_attrs, attrs, cfg_attrs)?;
+        } else {
+            attrs.push(attr);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,315 @@
+use proc_macro2::{Group, Ident, Punct, Spacing, Span, TokenStream, TokenTree};
+use syn::{
+    visit::Visit,
+    visit_mut::{self, VisitMut},
+};
+
+/// Crate-level inner attributes of the library that are moved to the root of the bundle
+const MOVED_ATTRIBUTES: &[&str] = &["feature"];
+/// Crate-level inner attributes of the library that are dropped, as they are invalid or harmful in a module
+const DROPPED_ATTRIBUTES: &[&str] = &[
+    "no_std",
+    "no_main",
+    "crate_type",
+    "crate_name",
+    "recursion_limit",
+    "type_length_limit",
+    "windows_subsystem",
+];
+
+pub(crate) fn bundle(mut bin: syn::File, lib: syn::File, lib_name: &str) -> syn::File {
+    let name = Ident::new(&lib_name.replace('-', "_"), Span::call_site());
+    let syn::File {
+        attrs: lib_attrs,
+        items: mut lib_items,
+        ..
+    } = lib;
+
+    let mut exported = ExportedMacros(Vec::new());
+    for item in &lib_items {
+        exported.visit_item(item);
+    }
+    for item in &mut lib_items {
+        LibRewriter { name: &name }.visit_item_mut(item);
+    }
+    if !exported.0.is_empty() {
+        // `#[macro_export]` macros end up in the root of the bundle, but are still accessible like `mylib::m!()`
+        let names = exported.0;
+        lib_items.insert(0, syn::parse_quote!(pub use crate::{#(#names),*};));
+    }
+
+    let mut attrs: Vec<syn::Attribute> = vec![syn::parse_quote!(#[allow(dead_code, unused_imports, unused_macros)])];
+    for attr in lib_attrs {
+        if MOVED_ATTRIBUTES.iter().any(|x| attr.path().is_ident(x)) {
+            if !bin.attrs.contains(&attr) {
+                bin.attrs.push(attr);
+            }
+        } else if !DROPPED_ATTRIBUTES.iter().any(|x| attr.path().is_ident(x)) {
+            attrs.push(attr);
+        }
+    }
+
+    let mut bin_rewriter = BinRewriter {
+        name: &name,
+        aliases: vec![name.clone()],
+        macro_use: false,
+    };
+    bin_rewriter.visit_file_mut(&mut bin);
+    if bin_rewriter.macro_use {
+        attrs.insert(0, syn::parse_quote!(#[macro_use]));
+    }
+
+    let module = syn::ItemMod {
+        attrs,
+        vis: syn::Visibility::Inherited,
+        unsafety: None,
+        mod_token: Default::default(),
+        ident: name,
+        content: Some((Default::default(), lib_items)),
+        semi: None,
+    };
+    // First, so that `#[macro_use]` macros are in textual scope of the whole binary
+    bin.items.insert(0, syn::Item::Mod(module));
+    bin
+}
+
+/// Names of `macro_rules!` macros with `#[macro_export]`, that are defined in the root of the crate wherever they are declared
+struct ExportedMacros(Vec<syn::Ident>);
+
+impl<'ast> Visit<'ast> for ExportedMacros {
+    fn visit_item_macro(&mut self, item: &'ast syn::ItemMacro) {
+        if let Some(ident) = &item.ident {
+            if item.attrs.iter().any(|x| x.path().is_ident("macro_export")) && !self.0.contains(ident) {
+                self.0.push(ident.clone());
+            }
+        }
+    }
+}
+
+fn is_double_colon(tokens: &[TokenTree]) -> bool {
+    matches!(
+        tokens,
+        [TokenTree::Punct(a), TokenTree::Punct(b), ..]
+            if a.as_char() == ':' && a.spacing() == Spacing::Joint && b.as_char() == ':'
+    )
+}
+
+fn double_colon(span: Span) -> [TokenTree; 2] {
+    let mut first = Punct::new(':', Spacing::Joint);
+    first.set_span(span);
+    let mut second = Punct::new(':', Spacing::Alone);
+    second.set_span(span);
+    [first.into(), second.into()]
+}
+
+/// Rewrite tokens of a macro invocation or definition: insert `inserted` tokens before (or after, followed by `::`)
+/// each identifier matching `prefix` that starts a path, i.e. is followed by `::` and is not preceded by `:`
+fn rewrite_tokens(
+    tokens: TokenStream,
+    prefix: &dyn Fn(&Ident) -> bool,
+    insert_before: bool,
+    inserted: &dyn Fn(Span) -> Vec<TokenTree>,
+) -> TokenStream {
+    let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+    let mut ret = Vec::with_capacity(tokens.len());
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            TokenTree::Group(group) => {
+                let mut new_group = Group::new(
+                    group.delimiter(),
+                    rewrite_tokens(group.stream(), prefix, insert_before, inserted),
+                );
+                new_group.set_span(group.span());
+                ret.push(TokenTree::Group(new_group));
+            }
+            TokenTree::Ident(ident)
+                if prefix(ident)
+                    && is_double_colon(&tokens[i + 1..])
+                    && !matches!(i.checked_sub(1).map(|x| &tokens[x]), Some(TokenTree::Punct(p)) if p.as_char() == ':') =>
+            {
+                if insert_before {
+                    ret.extend(inserted(ident.span()));
+                    ret.push(token.clone());
+                } else {
+                    ret.push(token.clone());
+                    ret.extend(double_colon(ident.span()));
+                    ret.extend(inserted(ident.span()));
+                }
+            }
+            _ => ret.push(token.clone()),
+        }
+    }
+    ret.into_iter().collect()
+}
+
+/// Rewrites `crate::` paths of the library to `crate::mylib::`, including `$crate::` in macros
+struct LibRewriter<'a> {
+    name: &'a Ident,
+}
+
+impl<'a> LibRewriter<'a> {
+    fn rewrite_use_tree(&self, tree: &mut syn::UseTree) {
+        match tree {
+            syn::UseTree::Path(path) if path.ident == "crate" => {
+                let rest = std::mem::replace(&mut *path.tree, syn::UseTree::Glob(syn::UseGlob { star_token: Default::default() }));
+                *path.tree = syn::UseTree::Path(syn::UsePath {
+                    ident: self.name.clone(),
+                    colon2_token: Default::default(),
+                    tree: Box::new(rest),
+                });
+            }
+            syn::UseTree::Group(group) => group.items.iter_mut().for_each(|x| self.rewrite_use_tree(x)),
+            _ => (),
+        }
+    }
+}
+
+impl<'a> VisitMut for LibRewriter<'a> {
+    fn visit_path_mut(&mut self, path: &mut syn::Path) {
+        if path.leading_colon.is_none() && path.segments.len() > 1 && path.segments[0].ident == "crate" {
+            path.segments.insert(1, self.name.clone().into());
+        }
+        visit_mut::visit_path_mut(self, path);
+    }
+
+    fn visit_vis_restricted_mut(&mut self, vis: &mut syn::VisRestricted) {
+        // `pub(crate)` keeps meaning the whole crate, `pub(in crate::x)` is rewritten
+        if vis.in_token.is_some() {
+            visit_mut::visit_vis_restricted_mut(self, vis);
+        }
+    }
+
+    fn visit_item_use_mut(&mut self, item: &mut syn::ItemUse) {
+        if item.leading_colon.is_none() {
+            self.rewrite_use_tree(&mut item.tree);
+        }
+    }
+
+    fn visit_macro_mut(&mut self, mac: &mut syn::Macro) {
+        let name = self.name.clone();
+        mac.tokens = rewrite_tokens(
+            std::mem::take(&mut mac.tokens),
+            &|x| x == "crate",
+            false,
+            &|span| {
+                let mut ident = name.clone();
+                ident.set_span(span);
+                vec![ident.into()]
+            },
+        );
+        visit_mut::visit_macro_mut(self, mac);
+    }
+}
+
+/// Rewrites `mylib::` paths of the binary to `crate::mylib::` and removes `extern crate mylib`
+struct BinRewriter<'a> {
+    name: &'a Ident,
+    /// The library name and names it is imported as with `extern crate mylib as other`
+    aliases: Vec<Ident>,
+    /// Whether there was `#[macro_use] extern crate mylib`
+    macro_use: bool,
+}
+
+impl<'a> BinRewriter<'a> {
+    fn rewrite_items(&mut self, items: &mut Vec<syn::Item>) {
+        items.retain_mut(|item| {
+            let syn::Item::ExternCrate(extern_crate) = item else { return true };
+            if extern_crate.ident != *self.name {
+                return true;
+            }
+            let mut attrs = std::mem::take(&mut extern_crate.attrs);
+            attrs.retain(|x| {
+                let macro_use = x.path().is_ident("macro_use");
+                self.macro_use |= macro_use;
+                !macro_use
+            });
+            // Not needed in 2018 edition, unless it is renamed
+            let Some((_, alias)) = &extern_crate.rename else { return false };
+            let alias = alias.clone();
+            if !self.aliases.contains(&alias) {
+                self.aliases.push(alias.clone());
+            }
+            let vis = &extern_crate.vis;
+            let name = self.name;
+            *item = syn::parse_quote!(#(#attrs)* #vis use crate::#name as #alias;);
+            true
+        });
+    }
+
+    fn is_alias(&self, ident: &Ident) -> bool {
+        self.aliases.contains(ident)
+    }
+
+    fn rewrite_use_tree(&self, tree: &mut syn::UseTree) {
+        let ident = match tree {
+            syn::UseTree::Path(x) => &x.ident,
+            syn::UseTree::Name(x) => &x.ident,
+            syn::UseTree::Rename(x) => &x.ident,
+            syn::UseTree::Group(group) => {
+                group.items.iter_mut().for_each(|x| self.rewrite_use_tree(x));
+                return;
+            }
+            syn::UseTree::Glob(_) => return,
+        };
+        if !self.is_alias(ident) {
+            return;
+        }
+        let rest = std::mem::replace(tree, syn::UseTree::Glob(syn::UseGlob { star_token: Default::default() }));
+        *tree = syn::UseTree::Path(syn::UsePath {
+            ident: Ident::new("crate", Span::call_site()),
+            colon2_token: Default::default(),
+            tree: Box::new(rest),
+        });
+    }
+}
+
+impl<'a> VisitMut for BinRewriter<'a> {
+    fn visit_file_mut(&mut self, file: &mut syn::File) {
+        self.rewrite_items(&mut file.items);
+        visit_mut::visit_file_mut(self, file);
+    }
+
+    fn visit_item_mod_mut(&mut self, item: &mut syn::ItemMod) {
+        if let Some((_, items)) = &mut item.content {
+            self.rewrite_items(items);
+        }
+        visit_mut::visit_item_mod_mut(self, item);
+    }
+
+    fn visit_path_mut(&mut self, path: &mut syn::Path) {
+        if (path.leading_colon.is_some() || path.segments.len() > 1) && self.is_alias(&path.segments[0].ident) {
+            path.leading_colon = None;
+            path.segments.insert(0, Ident::new("crate", Span::call_site()).into());
+        }
+        visit_mut::visit_path_mut(self, path);
+    }
+
+    fn visit_item_use_mut(&mut self, item: &mut syn::ItemUse) {
+        let ident = match &item.tree {
+            syn::UseTree::Path(x) => Some(&x.ident),
+            syn::UseTree::Name(x) => Some(&x.ident),
+            syn::UseTree::Rename(x) => Some(&x.ident),
+            _ => None,
+        };
+        if item.leading_colon.is_some() && !ident.is_some_and(|x| self.is_alias(x)) {
+            return;
+        }
+        item.leading_colon = None;
+        self.rewrite_use_tree(&mut item.tree);
+    }
+
+    fn visit_macro_mut(&mut self, mac: &mut syn::Macro) {
+        let aliases = &self.aliases;
+        mac.tokens = rewrite_tokens(
+            std::mem::take(&mut mac.tokens),
+            &|x| aliases.contains(x),
+            true,
+            &|span| {
+                let mut tokens = vec![Ident::new("crate", span).into()];
+                tokens.extend(double_colon(span));
+                tokens
+            },
+        );
+        visit_mut::visit_macro_mut(self, mac);
+    }
+}
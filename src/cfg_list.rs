@@ -0,0 +1,159 @@
+use std::path::{Path, PathBuf};
+
+use quote::ToTokens;
+use syn::{punctuated::Punctuated, visit::Visit, Meta, Token};
+
+use crate::{
+    delegate::Overrides, CfgKind, CfgLocation, CfgPredicate, Error, ModuleSource, Resolver,
+};
+
+/// Render a predicate in a canonical form: `feature = "x"`, `not(unix)`,
+/// `all(a, b)` with arguments of `all` and `any` sorted and de-duplicated.
+pub(crate) fn normalize_predicate(meta: &Meta) -> String {
+    match meta {
+        Meta::Path(path) => path_to_string(path),
+        Meta::NameValue(nv) => format!(
+            "{} = {}",
+            path_to_string(&nv.path),
+            nv.value.to_token_stream()
+        ),
+        Meta::List(list) => {
+            let Ok(nested) = list.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)
+            else {
+                return meta.to_token_stream().to_string();
+            };
+            let mut args: Vec<String> = nested.iter().map(normalize_predicate).collect();
+            if list.path.is_ident("all") || list.path.is_ident("any") {
+                args.sort();
+                args.dedup();
+            }
+            format!("{}({})", path_to_string(&list.path), args.join(", "))
+        }
+    }
+}
+
+fn path_to_string(path: &syn::Path) -> String {
+    path.to_token_stream().to_string().replace(' ', "")
+}
+
+/// Visitor that records `cfg` predicates of a single parsed file
+pub(crate) struct Collector<'a> {
+    pub file: Option<&'a Path>,
+    pub modules: Vec<syn::Ident>,
+    pub found: &'a mut Vec<(String, CfgLocation)>,
+}
+
+impl<'a> Collector<'a> {
+    fn record(&mut self, meta: &Meta, kind: CfgKind, span: proc_macro2::Span) {
+        self.found.push((
+            normalize_predicate(meta),
+            CfgLocation {
+                module: syn::Path {
+                    leading_colon: None,
+                    segments: self.modules.iter().cloned().map(syn::PathSegment::from).collect(),
+                },
+                file: self.file.map(ToOwned::to_owned),
+                line: crate::span_position(span).0,
+                kind,
+            },
+        ));
+    }
+
+    fn record_cfg_attr(&mut self, meta: &Meta, span: proc_macro2::Span) {
+        let Meta::List(list) = meta else { return };
+        let Ok(nested) = list.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated) else {
+            return;
+        };
+        let mut nested = nested.iter();
+        if let Some(condition) = nested.next() {
+            self.record(condition, CfgKind::CfgAttr, span);
+        }
+        for x in nested {
+            match x {
+                Meta::List(list) if list.path.is_ident("cfg") => {
+                    if let Ok(cfg) = list.parse_args() {
+                        self.record(&cfg, CfgKind::Cfg, span);
+                    }
+                }
+                _ if x.path().is_ident("cfg_attr") => self.record_cfg_attr(x, span),
+                _ => (),
+            }
+        }
+    }
+}
+
+impl<'a, 'ast> Visit<'ast> for Collector<'a> {
+    fn visit_attribute(&mut self, i: &'ast syn::Attribute) {
+        let span = i.pound_token.span;
+        if i.path().is_ident("cfg") {
+            if let Ok(cfg) = i.parse_args() {
+                self.record(&cfg, CfgKind::Cfg, span);
+            }
+        } else if i.path().is_ident("cfg_attr") {
+            self.record_cfg_attr(&i.meta, span);
+        }
+    }
+
+    fn visit_macro(&mut self, i: &'ast syn::Macro) {
+        if i.path.is_ident("cfg") {
+            if let Ok(cfg) = syn::parse2(i.tokens.clone()) {
+                self.record(&cfg, CfgKind::CfgMacro, i.path.segments[0].ident.span());
+            }
+        }
+    }
+
+    fn visit_item_mod(&mut self, i: &'ast syn::ItemMod) {
+        // Outer attributes are in the enclosing module, inner ones are in this one, like in a loaded file
+        let (outer, inner): (Vec<_>, Vec<_>) =
+            i.attrs.iter().partition(|x| matches!(x.style, syn::AttrStyle::Outer));
+        outer.into_iter().for_each(|x| self.visit_attribute(x));
+        self.modules.push(i.ident.clone());
+        inner.into_iter().for_each(|x| self.visit_attribute(x));
+        if let Some((_, items)) = &i.content {
+            items.iter().for_each(|x| self.visit_item(x));
+        }
+        self.modules.pop();
+    }
+}
+
+/// Group found predicates, sorted by predicate
+pub(crate) fn group(found: Vec<(String, CfgLocation)>) -> Vec<CfgPredicate> {
+    let mut ret: Vec<CfgPredicate> = Vec::new();
+    for (predicate, location) in found {
+        match ret.iter_mut().find(|x| x.predicate == predicate) {
+            Some(x) => x.locations.push(location),
+            None => ret.push(CfgPredicate {
+                predicate,
+                locations: vec![location],
+            }),
+        }
+    }
+    ret.sort_by(|a, b| a.predicate.cmp(&b.predicate));
+    ret
+}
+
+/// [`Overrides`] that record `cfg` predicates of each file loaded by the inner resolver
+pub(crate) struct Collecting<'a> {
+    pub found: &'a mut Vec<(String, CfgLocation)>,
+}
+
+impl<'a, R: Resolver> Overrides<R> for Collecting<'a> {
+    fn resolve_source(
+        &mut self,
+        inner: &mut R,
+        module_name: syn::Path,
+        path_relative_to_crate_root: PathBuf,
+    ) -> Result<Option<ModuleSource>, Error> {
+        let Some(source) = inner.resolve_source(module_name.clone(), path_relative_to_crate_root.clone())? else {
+            return Ok(None);
+        };
+        let file = source.parse(module_name.clone(), &path_relative_to_crate_root)?;
+        Collector {
+            file: Some(&path_relative_to_crate_root),
+            modules: module_name.segments.into_iter().map(|x| x.ident).collect(),
+            found: self.found,
+        }
+        .visit_file(&file);
+        Ok(Some(ModuleSource::Parsed(file)))
+    }
+}
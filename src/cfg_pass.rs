@@ -0,0 +1,440 @@
+use std::collections::VecDeque;
+
+use syn::{
+    punctuated::Punctuated, visit_mut::VisitMut, Attribute, Expr, GenericParam, Item, Meta, Stmt,
+    Token,
+};
+
+use crate::{AttrParseError, CfgOrigin, CfgQuery, Error, ErrorCase, UserError};
+
+/// Visitor that evaluates `#[cfg]` and `#[cfg_attr]` attributes in already expanded code
+pub(crate) struct CfgPass<'a> {
+    pub check: &'a mut dyn FnMut(CfgQuery) -> Result<bool, UserError>,
+    /// Remove nodes with false `#[cfg]`s. Otherwise only `#[cfg_attr]`s are expanded.
+    pub strip: bool,
+    /// Names of inline modules the visitor is currently in
+    pub modules: Vec<syn::Ident>,
+    /// First error encountered. Once it is set, the rest of the code is left as is.
+    pub error: Option<Error>,
+}
+
+impl<'a> CfgPass<'a> {
+    pub(crate) fn new(
+        check: &'a mut dyn FnMut(CfgQuery) -> Result<bool, UserError>,
+        strip: bool,
+    ) -> Self {
+        CfgPass {
+            check,
+            strip,
+            modules: vec![],
+            error: None,
+        }
+    }
+
+    fn module_path(&self) -> syn::Path {
+        syn::Path {
+            leading_colon: None,
+            segments: self.modules.iter().cloned().map(syn::PathSegment::from).collect(),
+        }
+    }
+
+    fn fail(&mut self, inner: ErrorCase) {
+        if self.error.is_none() {
+            self.error = Some(Error {
+                module: self.module_path(),
+                inner,
+            });
+        }
+    }
+
+    fn eval(&mut self, cfg: Meta, origin: CfgOrigin) -> Result<bool, ErrorCase> {
+        let module = self.module_path();
+        (self.check)(CfgQuery {
+            cfg,
+            module,
+            file: None,
+            origin,
+            candidate_path: None,
+        })
+        .map_err(ErrorCase::ErrorFromCallback)
+    }
+
+    /// Replace each `#[cfg_attr(pred, attrs...)]` with `attrs` if `pred` is true or remove it otherwise.
+    fn expand_cfg_attrs(&mut self, attrs: &mut Vec<Attribute>) -> Result<(), ErrorCase> {
+        if !attrs.iter().any(|x| x.path().is_ident("cfg_attr")) {
+            return Ok(());
+        }
+        let mut queue: VecDeque<Attribute> = std::mem::take(attrs).into();
+        while let Some(attr) = queue.pop_front() {
+            if !attr.path().is_ident("cfg_attr") {
+                attrs.push(attr);
+                continue;
+            }
+            let (condition, nested) = parse_cfg_attr(&attr).map_err(ErrorCase::AttrParseError)?;
+            if self.eval(condition, CfgOrigin::CfgAttr)? {
+                // Nested attributes may be `cfg_attr`s themselves, so they go back to the queue.
+                for meta in nested.into_iter().rev() {
+                    queue.push_front(Attribute {
+                        meta,
+                        ..attr.clone()
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Expand `#[cfg_attr]`s of a node that cannot be removed
+    fn normalize(&mut self, attrs: &mut Vec<Attribute>) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.expand_cfg_attrs(attrs) {
+            self.fail(e);
+        }
+    }
+
+    /// Process attributes of a node that can be removed.
+    /// Returns `false` if the node should be removed because one of its `#[cfg]`s is false,
+    /// otherwise removes `#[cfg]`s and expands `#[cfg_attr]`s.
+    fn keep(&mut self, attrs: &mut Vec<Attribute>) -> bool {
+        if self.error.is_some() {
+            return true;
+        }
+        match self.keep_impl(attrs) {
+            Ok(x) => x,
+            Err(e) => {
+                self.fail(e);
+                true
+            }
+        }
+    }
+
+    fn keep_impl(&mut self, attrs: &mut Vec<Attribute>) -> Result<bool, ErrorCase> {
+        self.expand_cfg_attrs(attrs)?;
+        if !self.strip {
+            return Ok(true);
+        }
+        for attr in attrs.iter() {
+            if !attr.path().is_ident("cfg") {
+                continue;
+            }
+            let cfg = attr
+                .parse_args()
+                .map_err(|_| ErrorCase::AttrParseError(AttrParseError::MalformedCfg))?;
+            if !self.eval(cfg, CfgOrigin::ItemCfg)? {
+                return Ok(false);
+            }
+        }
+        attrs.retain(|x| !x.path().is_ident("cfg"));
+        Ok(true)
+    }
+
+    fn retain<T>(&mut self, v: &mut Vec<T>, attrs: impl Fn(&mut T) -> Option<&mut Vec<Attribute>>) {
+        v.retain_mut(|x| match attrs(x) {
+            Some(attrs) => self.keep(attrs),
+            None => true,
+        });
+    }
+
+    fn retain_punctuated<T, P>(
+        &mut self,
+        p: &mut Punctuated<T, P>,
+        attrs: impl Fn(&mut T) -> Option<&mut Vec<Attribute>>,
+    ) {
+        for pair in std::mem::take(p).into_pairs() {
+            let (mut x, punct) = pair.into_tuple();
+            let keep = match attrs(&mut x) {
+                Some(attrs) => self.keep(attrs),
+                None => true,
+            };
+            if keep {
+                p.push_value(x);
+                if let Some(punct) = punct {
+                    p.push_punct(punct);
+                }
+            }
+        }
+    }
+}
+
+fn parse_cfg_attr(attr: &Attribute) -> Result<(Meta, Vec<Meta>), AttrParseError> {
+    if !matches!(attr.meta, Meta::List(..)) {
+        return Err(AttrParseError::CfgAttrNotRoundGroup);
+    }
+    let nested = attr
+        .parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)
+        .map_err(|_| AttrParseError::MalformedCfg)?;
+    let mut nested = nested.into_iter();
+    let Some(condition) = nested.next() else {
+        return Err(AttrParseError::CfgAttrNotTwoParams);
+    };
+    Ok((condition, nested.collect()))
+}
+
+pub(crate) fn item_attrs(item: &mut Item) -> Option<&mut Vec<Attribute>> {
+    Some(match item {
+        Item::Const(x) => &mut x.attrs,
+        Item::Enum(x) => &mut x.attrs,
+        Item::ExternCrate(x) => &mut x.attrs,
+        Item::Fn(x) => &mut x.attrs,
+        Item::ForeignMod(x) => &mut x.attrs,
+        Item::Impl(x) => &mut x.attrs,
+        Item::Macro(x) => &mut x.attrs,
+        Item::Mod(x) => &mut x.attrs,
+        Item::Static(x) => &mut x.attrs,
+        Item::Struct(x) => &mut x.attrs,
+        Item::Trait(x) => &mut x.attrs,
+        Item::TraitAlias(x) => &mut x.attrs,
+        Item::Type(x) => &mut x.attrs,
+        Item::Union(x) => &mut x.attrs,
+        Item::Use(x) => &mut x.attrs,
+        _ => return None,
+    })
+}
+
+fn impl_item_attrs(item: &mut syn::ImplItem) -> Option<&mut Vec<Attribute>> {
+    Some(match item {
+        syn::ImplItem::Const(x) => &mut x.attrs,
+        syn::ImplItem::Fn(x) => &mut x.attrs,
+        syn::ImplItem::Type(x) => &mut x.attrs,
+        syn::ImplItem::Macro(x) => &mut x.attrs,
+        _ => return None,
+    })
+}
+
+fn trait_item_attrs(item: &mut syn::TraitItem) -> Option<&mut Vec<Attribute>> {
+    Some(match item {
+        syn::TraitItem::Const(x) => &mut x.attrs,
+        syn::TraitItem::Fn(x) => &mut x.attrs,
+        syn::TraitItem::Type(x) => &mut x.attrs,
+        syn::TraitItem::Macro(x) => &mut x.attrs,
+        _ => return None,
+    })
+}
+
+fn foreign_item_attrs(item: &mut syn::ForeignItem) -> Option<&mut Vec<Attribute>> {
+    Some(match item {
+        syn::ForeignItem::Fn(x) => &mut x.attrs,
+        syn::ForeignItem::Static(x) => &mut x.attrs,
+        syn::ForeignItem::Type(x) => &mut x.attrs,
+        syn::ForeignItem::Macro(x) => &mut x.attrs,
+        _ => return None,
+    })
+}
+
+fn stmt_attrs(stmt: &mut Stmt) -> Option<&mut Vec<Attribute>> {
+    match stmt {
+        Stmt::Local(x) => Some(&mut x.attrs),
+        Stmt::Item(x) => item_attrs(x),
+        Stmt::Expr(x, _) => expr_attrs(x),
+        Stmt::Macro(x) => Some(&mut x.attrs),
+    }
+}
+
+fn expr_attrs(expr: &mut Expr) -> Option<&mut Vec<Attribute>> {
+    Some(match expr {
+        Expr::Array(x) => &mut x.attrs,
+        Expr::Assign(x) => &mut x.attrs,
+        Expr::Async(x) => &mut x.attrs,
+        Expr::Await(x) => &mut x.attrs,
+        Expr::Binary(x) => &mut x.attrs,
+        Expr::Block(x) => &mut x.attrs,
+        Expr::Break(x) => &mut x.attrs,
+        Expr::Call(x) => &mut x.attrs,
+        Expr::Cast(x) => &mut x.attrs,
+        Expr::Closure(x) => &mut x.attrs,
+        Expr::Const(x) => &mut x.attrs,
+        Expr::Continue(x) => &mut x.attrs,
+        Expr::Field(x) => &mut x.attrs,
+        Expr::ForLoop(x) => &mut x.attrs,
+        Expr::Group(x) => &mut x.attrs,
+        Expr::If(x) => &mut x.attrs,
+        Expr::Index(x) => &mut x.attrs,
+        Expr::Infer(x) => &mut x.attrs,
+        Expr::Let(x) => &mut x.attrs,
+        Expr::Lit(x) => &mut x.attrs,
+        Expr::Loop(x) => &mut x.attrs,
+        Expr::Macro(x) => &mut x.attrs,
+        Expr::Match(x) => &mut x.attrs,
+        Expr::MethodCall(x) => &mut x.attrs,
+        Expr::Paren(x) => &mut x.attrs,
+        Expr::Path(x) => &mut x.attrs,
+        Expr::Range(x) => &mut x.attrs,
+        Expr::Reference(x) => &mut x.attrs,
+        Expr::Repeat(x) => &mut x.attrs,
+        Expr::Return(x) => &mut x.attrs,
+        Expr::Struct(x) => &mut x.attrs,
+        Expr::Try(x) => &mut x.attrs,
+        Expr::TryBlock(x) => &mut x.attrs,
+        Expr::Tuple(x) => &mut x.attrs,
+        Expr::Unary(x) => &mut x.attrs,
+        Expr::Unsafe(x) => &mut x.attrs,
+        Expr::While(x) => &mut x.attrs,
+        Expr::Yield(x) => &mut x.attrs,
+        _ => return None,
+    })
+}
+
+fn pat_attrs(pat: &mut syn::Pat) -> Option<&mut Vec<Attribute>> {
+    Some(match pat {
+        syn::Pat::Const(x) => &mut x.attrs,
+        syn::Pat::Ident(x) => &mut x.attrs,
+        syn::Pat::Lit(x) => &mut x.attrs,
+        syn::Pat::Macro(x) => &mut x.attrs,
+        syn::Pat::Or(x) => &mut x.attrs,
+        syn::Pat::Paren(x) => &mut x.attrs,
+        syn::Pat::Path(x) => &mut x.attrs,
+        syn::Pat::Range(x) => &mut x.attrs,
+        syn::Pat::Reference(x) => &mut x.attrs,
+        syn::Pat::Rest(x) => &mut x.attrs,
+        syn::Pat::Slice(x) => &mut x.attrs,
+        syn::Pat::Struct(x) => &mut x.attrs,
+        syn::Pat::Tuple(x) => &mut x.attrs,
+        syn::Pat::TupleStruct(x) => &mut x.attrs,
+        syn::Pat::Type(x) => &mut x.attrs,
+        syn::Pat::Wild(x) => &mut x.attrs,
+        _ => return None,
+    })
+}
+
+fn generic_param_attrs(param: &mut GenericParam) -> Option<&mut Vec<Attribute>> {
+    Some(match param {
+        GenericParam::Lifetime(x) => &mut x.attrs,
+        GenericParam::Type(x) => &mut x.attrs,
+        GenericParam::Const(x) => &mut x.attrs,
+    })
+}
+
+fn fn_arg_attrs(arg: &mut syn::FnArg) -> Option<&mut Vec<Attribute>> {
+    Some(match arg {
+        syn::FnArg::Receiver(x) => &mut x.attrs,
+        syn::FnArg::Typed(x) => &mut x.attrs,
+    })
+}
+
+impl<'a> VisitMut for CfgPass<'a> {
+    fn visit_file_mut(&mut self, i: &mut syn::File) {
+        self.normalize(&mut i.attrs);
+        self.retain(&mut i.items, item_attrs);
+        syn::visit_mut::visit_file_mut(self, i);
+    }
+
+    fn visit_item_mod_mut(&mut self, i: &mut syn::ItemMod) {
+        self.modules.push(i.ident.clone());
+        if let Some((_, items)) = &mut i.content {
+            self.retain(items, item_attrs);
+        }
+        syn::visit_mut::visit_item_mod_mut(self, i);
+        self.modules.pop();
+    }
+
+    fn visit_item_impl_mut(&mut self, i: &mut syn::ItemImpl) {
+        self.retain(&mut i.items, impl_item_attrs);
+        syn::visit_mut::visit_item_impl_mut(self, i);
+    }
+
+    fn visit_item_trait_mut(&mut self, i: &mut syn::ItemTrait) {
+        self.retain(&mut i.items, trait_item_attrs);
+        syn::visit_mut::visit_item_trait_mut(self, i);
+    }
+
+    fn visit_item_foreign_mod_mut(&mut self, i: &mut syn::ItemForeignMod) {
+        self.retain(&mut i.items, foreign_item_attrs);
+        syn::visit_mut::visit_item_foreign_mod_mut(self, i);
+    }
+
+    fn visit_item_enum_mut(&mut self, i: &mut syn::ItemEnum) {
+        self.retain_punctuated(&mut i.variants, |x| Some(&mut x.attrs));
+        syn::visit_mut::visit_item_enum_mut(self, i);
+    }
+
+    fn visit_fields_named_mut(&mut self, i: &mut syn::FieldsNamed) {
+        self.retain_punctuated(&mut i.named, |x| Some(&mut x.attrs));
+        syn::visit_mut::visit_fields_named_mut(self, i);
+    }
+
+    fn visit_fields_unnamed_mut(&mut self, i: &mut syn::FieldsUnnamed) {
+        self.retain_punctuated(&mut i.unnamed, |x| Some(&mut x.attrs));
+        syn::visit_mut::visit_fields_unnamed_mut(self, i);
+    }
+
+    fn visit_generics_mut(&mut self, i: &mut syn::Generics) {
+        self.retain_punctuated(&mut i.params, generic_param_attrs);
+        syn::visit_mut::visit_generics_mut(self, i);
+    }
+
+    fn visit_signature_mut(&mut self, i: &mut syn::Signature) {
+        self.retain_punctuated(&mut i.inputs, fn_arg_attrs);
+        syn::visit_mut::visit_signature_mut(self, i);
+    }
+
+    fn visit_block_mut(&mut self, i: &mut syn::Block) {
+        self.retain(&mut i.stmts, stmt_attrs);
+        syn::visit_mut::visit_block_mut(self, i);
+    }
+
+    fn visit_expr_match_mut(&mut self, i: &mut syn::ExprMatch) {
+        self.retain(&mut i.arms, |x| Some(&mut x.attrs));
+        syn::visit_mut::visit_expr_match_mut(self, i);
+    }
+
+    fn visit_expr_struct_mut(&mut self, i: &mut syn::ExprStruct) {
+        self.retain_punctuated(&mut i.fields, |x| Some(&mut x.attrs));
+        syn::visit_mut::visit_expr_struct_mut(self, i);
+    }
+
+    fn visit_pat_struct_mut(&mut self, i: &mut syn::PatStruct) {
+        self.retain_punctuated(&mut i.fields, |x| Some(&mut x.attrs));
+        syn::visit_mut::visit_pat_struct_mut(self, i);
+    }
+
+    fn visit_expr_array_mut(&mut self, i: &mut syn::ExprArray) {
+        self.retain_punctuated(&mut i.elems, expr_attrs);
+        syn::visit_mut::visit_expr_array_mut(self, i);
+    }
+
+    fn visit_expr_tuple_mut(&mut self, i: &mut syn::ExprTuple) {
+        self.retain_punctuated(&mut i.elems, expr_attrs);
+        syn::visit_mut::visit_expr_tuple_mut(self, i);
+    }
+
+    fn visit_expr_call_mut(&mut self, i: &mut syn::ExprCall) {
+        self.retain_punctuated(&mut i.args, expr_attrs);
+        syn::visit_mut::visit_expr_call_mut(self, i);
+    }
+
+    fn visit_expr_method_call_mut(&mut self, i: &mut syn::ExprMethodCall) {
+        self.retain_punctuated(&mut i.args, expr_attrs);
+        syn::visit_mut::visit_expr_method_call_mut(self, i);
+    }
+
+    fn visit_expr_mut(&mut self, i: &mut Expr) {
+        if let Some(attrs) = expr_attrs(i) {
+            self.normalize(attrs);
+        }
+        syn::visit_mut::visit_expr_mut(self, i);
+    }
+
+    fn visit_pat_mut(&mut self, i: &mut syn::Pat) {
+        if let Some(attrs) = pat_attrs(i) {
+            self.normalize(attrs);
+        }
+        syn::visit_mut::visit_pat_mut(self, i);
+    }
+
+    fn visit_bare_fn_arg_mut(&mut self, i: &mut syn::BareFnArg) {
+        self.normalize(&mut i.attrs);
+        syn::visit_mut::visit_bare_fn_arg_mut(self, i);
+    }
+
+    fn visit_bare_variadic_mut(&mut self, i: &mut syn::BareVariadic) {
+        self.normalize(&mut i.attrs);
+        syn::visit_mut::visit_bare_variadic_mut(self, i);
+    }
+
+    fn visit_variadic_mut(&mut self, i: &mut syn::Variadic) {
+        self.normalize(&mut i.attrs);
+        syn::visit_mut::visit_variadic_mut(self, i);
+    }
+}
@@ -0,0 +1,92 @@
+use std::path::PathBuf;
+
+use crate::{CfgQuery, Error, ModuleSource, Resolver, UserError};
+
+/// Methods of [`Resolver`] that a [`Delegating`] resolver may override.
+/// Default implementations call the inner resolver.
+pub(crate) trait Overrides<R: Resolver> {
+    fn resolve_source(
+        &mut self,
+        inner: &mut R,
+        module_name: syn::Path,
+        path_relative_to_crate_root: PathBuf,
+    ) -> Result<Option<ModuleSource>, Error> {
+        inner.resolve_source(module_name, path_relative_to_crate_root)
+    }
+
+    fn check_cfg_query(&mut self, inner: &mut R, query: CfgQuery) -> Result<bool, UserError> {
+        inner.check_cfg_query(query)
+    }
+
+    fn allow_duplicate_modules_and_convert_cfg(&mut self, inner: &mut R) -> bool {
+        inner.allow_duplicate_modules_and_convert_cfg()
+    }
+}
+
+/// [`Resolver`] that forwards every method to `inner`, except for those overridden by `overrides`.
+/// This is the only place to update when a method is added to [`Resolver`].
+pub(crate) struct Delegating<'a, R, O> {
+    pub inner: &'a mut R,
+    pub overrides: O,
+}
+
+impl<'a, R: Resolver, O: Overrides<R>> Resolver for Delegating<'a, R, O> {
+    fn resolve(
+        &mut self,
+        module_name: syn::Path,
+        path_relative_to_crate_root: PathBuf,
+    ) -> Result<Option<syn::File>, Error> {
+        self.resolve_source(module_name.clone(), path_relative_to_crate_root.clone())?
+            .map(|x| x.parse(module_name, &path_relative_to_crate_root))
+            .transpose()
+    }
+
+    fn resolve_source(
+        &mut self,
+        module_name: syn::Path,
+        path_relative_to_crate_root: PathBuf,
+    ) -> Result<Option<ModuleSource>, Error> {
+        self.overrides
+            .resolve_source(self.inner, module_name, path_relative_to_crate_root)
+    }
+
+    fn check_cfg(&mut self, cfg: syn::Meta) -> Result<bool, UserError> {
+        self.inner.check_cfg(cfg)
+    }
+
+    fn check_cfg_query(&mut self, query: CfgQuery) -> Result<bool, UserError> {
+        self.overrides.check_cfg_query(self.inner, query)
+    }
+
+    fn allow_duplicate_modules_and_convert_cfg(&mut self) -> bool {
+        self.overrides.allow_duplicate_modules_and_convert_cfg(self.inner)
+    }
+
+    fn first_path_attribute_wins(&mut self) -> bool {
+        self.inner.first_path_attribute_wins()
+    }
+
+    fn merge_identical_modules(&mut self) -> bool {
+        self.inner.merge_identical_modules()
+    }
+
+    fn error_on_unresolved_modules(&mut self) -> bool {
+        self.inner.error_on_unresolved_modules()
+    }
+
+    fn max_depth(&mut self) -> Option<usize> {
+        self.inner.max_depth()
+    }
+
+    fn expand_item_macro(
+        &mut self,
+        module: syn::Path,
+        item_macro: &syn::ItemMacro,
+    ) -> Result<Option<Vec<syn::Item>>, UserError> {
+        self.inner.expand_item_macro(module, item_macro)
+    }
+
+    fn evaluate_macro_rules(&mut self) -> bool {
+        self.inner.evaluate_macro_rules()
+    }
+}
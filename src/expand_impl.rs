@@ -1,12 +1,47 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use im_rc::Vector;
+#[cfg(feature = "span-locations")]
+use proc_macro2::LineColumn;
 use proc_macro2::TokenStream;
 use quote::ToTokens;
-use syn::{punctuated::Punctuated, spanned::Spanned, MetaList, Token};
+use syn::{ext::IdentExt, punctuated::Punctuated, spanned::Spanned, visit_mut::VisitMut, MetaList, Token};
 
-use crate::{attrs, Error, ErrorCase, Resolver};
+use crate::{
+    attrs, cfg_pass, macro_rules::MacroRules, AttrParseError, CfgOrigin, CfgQuery, Error, ErrorCase, ModuleTree, Resolver, UnexpandedMacro, UnresolvedModule,
+    UnresolvedReason, UserError,
+};
 
+/// Information about the expansion process that is not reflected in the resulting code
+#[derive(Default)]
+pub(crate) struct Diagnostics {
+    /// Modules that were not expanded because their `#[cfg]` was false, with that `cfg`
+    pub skipped: Vec<(syn::Path, syn::Meta)>,
+    /// Modules that were left unexpanded because no file was loaded for them
+    pub unresolved: Vec<UnresolvedModule>,
+    /// Local macro invocations that [`Resolver::evaluate_macro_rules`] could not expand
+    pub unexpanded_macros: Vec<UnexpandedMacro>,
+    /// `macro_rules!` definitions in scope of the module being expanded, innermost last
+    pub macro_scope: Vec<MacroRules>,
+    /// `mod name;` declarations replaced by file contents, recorded outside multimodule mode
+    #[cfg(feature = "span-locations")]
+    pub declarations: Vec<ExpandedDeclaration>,
+}
+
+/// Location of a `mod name;` declaration that was expanded, used to splice file texts
+#[cfg(feature = "span-locations")]
+pub(crate) struct ExpandedDeclaration {
+    /// File containing the declaration, `None` for the root file
+    pub parent: Option<PathBuf>,
+    /// Position of the `;` in that file
+    pub semicolon: LineColumn,
+    /// Start and end of the `#[path]` and `#[cfg_attr(..., path = ...)]` attributes of the declaration
+    pub path_attrs: Vec<(LineColumn, LineColumn)>,
+    /// File the module was loaded from
+    pub file: PathBuf,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn expand_impl<R: Resolver>(
     content: &mut Vec<syn::Item>,
     resolver: &mut R,
@@ -14,23 +49,68 @@ pub(crate) fn expand_impl<R: Resolver>(
     relative_path_where_to_look_for_nested_modules_naturally: Vector<PathBuf>,
     relative_path_where_to_look_for_nested_modules_when_using_path_attribute: Vector<PathBuf>,
     multimodule_mode: bool,
+    in_block: bool,
+    current_file: Option<&Path>,
+    tree: &mut Vec<ModuleTree>,
+    diagnostics: &mut Diagnostics,
 ) -> Result<(), Error> {
+    let macro_scope_len = diagnostics.macro_scope.len();
+    let scopes = expand_item_macros(content, resolver, &modules_stack, multimodule_mode, current_file, diagnostics, 0)?;
+    // Definitions of this module are only visible to modules declared after them, so bring them back one by one
+    let mut definitions = diagnostics.macro_scope.split_off(macro_scope_len).into_iter();
     let mut multimodule_tmp_container: Vec<syn::Item> = if multimodule_mode {
         Vec::with_capacity(content.len())
     } else {
         Vec::new()
     };
-    'items_loop: for item in &mut *content {
+    'items_loop: for (item, scope_len) in content.iter_mut().zip(scopes) {
+        let visible = scope_len - diagnostics.macro_scope.len();
+        diagnostics.macro_scope.extend(definitions.by_ref().take(visible));
         let (item_mod, semicolon_after_module_declaration) = match item {
             syn::Item::Mod(ref mut item_mod) => {
                 match (&item_mod.content, item_mod.semi) {
                     (None, None) => panic!("A module without both `{{}}` and `;`?"),
                     (Some(_), Some(_)) => panic!("A module with both `{{}}` and `;`?"),
-                    (Some(_), None) => continue, // inline module
+                    (Some(_), None) => {
+                        expand_inline_module(
+                            item_mod,
+                            resolver,
+                            &modules_stack,
+                            if in_block {
+                                &relative_path_where_to_look_for_nested_modules_when_using_path_attribute
+                            } else {
+                                &relative_path_where_to_look_for_nested_modules_naturally
+                            },
+                            &relative_path_where_to_look_for_nested_modules_when_using_path_attribute,
+                            multimodule_mode,
+                            in_block,
+                            current_file,
+                            tree,
+                            diagnostics,
+                        )?;
+                        if multimodule_mode {
+                            multimodule_tmp_container.push(item.clone());
+                        }
+                        continue;
+                    }
                     (None, Some(semi)) => (item_mod, semi),
                 }
             }
             _ => {
+                let mut visitor = BlockModules {
+                    resolver: &mut *resolver,
+                    modules_stack: &modules_stack,
+                    dirs: &relative_path_where_to_look_for_nested_modules_when_using_path_attribute,
+                    multimodule_mode,
+                    current_file,
+                    tree: &mut *tree,
+                    diagnostics: &mut *diagnostics,
+                    error: None,
+                };
+                visitor.visit_item_mut(item);
+                if let Some(e) = visitor.error {
+                    return Err(e);
+                }
                 if multimodule_mode {
                     multimodule_tmp_container.push(item.clone());
                 }
@@ -43,8 +123,10 @@ pub(crate) fn expand_impl<R: Resolver>(
         let mut inner_stack = modules_stack.clone();
         inner_stack.push_back(id.clone());
 
-        let chunk = PathBuf::from(format!("{}", id));
-        let chunk_rs = PathBuf::from(format!("{}.rs", id));
+        // `mod r#type;` is looked up in `type.rs`, like the compiler does
+        let file_stem = id.unraw();
+        let chunk = PathBuf::from(format!("{}", file_stem));
+        let chunk_rs = PathBuf::from(format!("{}.rs", file_stem));
 
         let mut dirs_nat = relative_path_where_to_look_for_nested_modules_naturally.clone();
         let mut dirs_attr =
@@ -86,6 +168,10 @@ pub(crate) fn expand_impl<R: Resolver>(
 
         struct ExpandedModuleInfo {
             result: Option<syn::File>,
+            /// File `result` was loaded from
+            file: Option<PathBuf>,
+            /// Filled in if `result` is `None`
+            unresolved: Option<UnresolvedModule>,
             /// Root path to use for recursive expansions for inner `#[path]` modules ("attribute" path)
             dirs_attr: Vector<PathBuf>,
             /// Root path to use for recursive expansions for inner modules without `#[path]` ("natural" path)
@@ -111,21 +197,44 @@ pub(crate) fn expand_impl<R: Resolver>(
             inner: ErrorCase::AttrParseError(e),
         })?;
 
+        let mut module_cfgs = Vec::with_capacity(cfg_attrs.len());
         for cfg in cfg_attrs {
             let cfg: syn::Meta = syn::parse2(cfg).map_err(|e| err(ErrorCase::SynParseError(e)))?;
-            if !resolver.check_cfg(cfg).map_err(|e| Error {
+            let query = CfgQuery {
+                cfg: cfg.clone(),
+                module: mod_syn_path.clone(),
+                file: current_file.map(ToOwned::to_owned),
+                origin: CfgOrigin::Cfg,
+                candidate_path: None,
+            };
+            if !resolver.check_cfg_query(query).map_err(|e| Error {
                 module: mod_syn_path.clone(),
                 inner: ErrorCase::ErrorFromCallback(e),
             })? {
+                diagnostics.skipped.push((mod_syn_path, cfg));
                 continue 'items_loop;
             }
+            module_cfgs.push(cfg);
         }
 
+        if let Some(max_depth) = resolver.max_depth() {
+            if inner_stack.len() > max_depth {
+                return Err(err(ErrorCase::MaxDepthExceeded { max_depth }));
+            }
+        }
+
+        let some_span = item_mod.span();
         let mut need_to_try_natural_file_locations = true;
         let mut accumulated_cfgs = Vec::<syn::Meta>::new();
+        let first_path_wins = resolver.first_path_attribute_wins();
+        // With `first_path_wins`, set when no further `#[path]` attribute can take effect
+        let mut path_settled = false;
 
         for (explicit_path, condition_in_cfg_attr_path) in path_attrs {
-            if condition_in_cfg_attr_path.is_none() && !expansion_candidates.is_empty() && !multimodule_mode {
+            if path_settled {
+                continue;
+            }
+            if condition_in_cfg_attr_path.is_none() && !expansion_candidates.is_empty() && !multimodule_mode && !first_path_wins {
                 return Err(err(ErrorCase::MultipleExplicitPathsSpecifiedForOneModule));
             }
 
@@ -144,8 +253,15 @@ pub(crate) fn expand_impl<R: Resolver>(
             if let Some(cfg) = condition_in_cfg_attr_path {
                 let cfg: syn::Meta =
                     syn::parse2(cfg).map_err(|e| err(ErrorCase::SynParseError(e)))?;
+                let query = CfgQuery {
+                    cfg: cfg.clone(),
+                    module: mod_syn_path.clone(),
+                    file: current_file.map(ToOwned::to_owned),
+                    origin: CfgOrigin::CfgAttrPath,
+                    candidate_path: Some(module_file_explicit.clone()),
+                };
                 if resolver
-                    .check_cfg(cfg.clone())
+                    .check_cfg_query(query)
                     .map_err(|e| err(ErrorCase::ErrorFromCallback(e)))?
                 {
                     if !expansion_candidates.is_empty() && !multimodule_mode {
@@ -153,32 +269,61 @@ pub(crate) fn expand_impl<R: Resolver>(
                     }
                     if !multimodule_mode {
                         need_to_try_natural_file_locations = false;
+                        path_settled = first_path_wins;
                     }
-                    let result = resolver.resolve(mod_syn_path.clone(), module_file_explicit)?;
+                    let injected_cfg = if first_path_wins && !accumulated_cfgs.is_empty() {
+                        // Gate it by previous conditions being false: `all(this, not(previous))`
+                        cfg_combinator(
+                            some_span,
+                            "all",
+                            [cfg.clone(), none_of(some_span, &accumulated_cfgs)],
+                        )
+                    } else {
+                        cfg.clone()
+                    };
+                    let result = load(resolver, &mod_syn_path, module_file_explicit.clone())?;
                     expansion_candidates.push(ExpandedModuleInfo {
+                        unresolved: declined(&result, &mod_syn_path, vec![module_file_explicit.clone()]),
+                        file: result.as_ref().map(|_| module_file_explicit),
                         result,
                         dirs_attr: dirs_candidate.clone(),
                         dirs_nat: dirs_candidate,
-                        injected_cfg: Some(cfg.clone()),
+                        injected_cfg: Some(injected_cfg),
                     });
                     accumulated_cfgs.push(cfg);
                 }
             } else {
                 need_to_try_natural_file_locations = false;
-                let result = resolver.resolve(mod_syn_path.clone(), module_file_explicit)?;
+                path_settled = first_path_wins;
+                let injected_cfg = if first_path_wins && multimodule_mode && !accumulated_cfgs.is_empty() {
+                    Some(none_of(some_span, &accumulated_cfgs))
+                } else {
+                    None
+                };
+                let result = load(resolver, &mod_syn_path, module_file_explicit.clone())?;
                 expansion_candidates.push(ExpandedModuleInfo {
+                    unresolved: declined(&result, &mod_syn_path, vec![module_file_explicit.clone()]),
+                    file: result.as_ref().map(|_| module_file_explicit),
                     result,
                     dirs_attr: dirs_candidate.clone(),
                     dirs_nat: dirs_candidate,
-                    injected_cfg: None,
+                    injected_cfg,
                 });
             }
         }
 
         assert!(multimodule_mode || expansion_candidates.len() <= 1);
 
+        if in_block && need_to_try_natural_file_locations {
+            // rustc does not look for such modules, unless it is another configuration's problem
+            if !multimodule_mode || accumulated_cfgs.is_empty() {
+                return Err(err(ErrorCase::ModuleInBlockWithoutPath));
+            }
+            need_to_try_natural_file_locations = false;
+        }
+
         if need_to_try_natural_file_locations {
-            let inner_nomod = resolver.resolve(mod_syn_path.clone(), module_file_nomod);
+            let inner_nomod = load(resolver, &mod_syn_path, module_file_nomod.clone());
             match inner_nomod {
                 Ok(_) => (),
                 Err(Error {
@@ -187,7 +332,7 @@ pub(crate) fn expand_impl<R: Resolver>(
                 }) => (),
                 Err(e) => return Err(e),
             }
-            let inner_mod = resolver.resolve(mod_syn_path.clone(), module_file_mod.clone());
+            let inner_mod = load(resolver, &mod_syn_path, module_file_mod.clone());
             match inner_mod {
                 Ok(_) => (),
                 Err(Error {
@@ -196,20 +341,27 @@ pub(crate) fn expand_impl<R: Resolver>(
                 }) => (),
                 Err(e) => return Err(e),
             }
-            let result = match (inner_nomod, inner_mod) {
+            let unresolved = |reason| {
+                Some(UnresolvedModule {
+                    module: mod_syn_path.clone(),
+                    candidates: vec![module_file_nomod.clone(), module_file_mod.clone()],
+                    reason,
+                })
+            };
+            let (result, file, unresolved) = match (inner_nomod, inner_mod) {
                 (Ok(Some(_)), Ok(Some(_))) => {
                     return Err(err(ErrorCase::BothModRsAndNameRsPresent))
                 }
-                (Ok(None), Ok(None)) => None,
+                (Ok(None), Ok(None)) => (None, None, unresolved(UnresolvedReason::Declined)),
                 (Ok(Some(x)), _) => {
                     dirs_attr = dirs_nat.clone();
-                    dirs_nat.push_back(PathBuf::from(chunk.clone()));
-                    Some(x)
+                    dirs_nat.push_back(chunk.clone());
+                    (Some(x), Some(module_file_nomod), None)
                 }
                 (_, Ok(Some(x))) => {
-                    dirs_nat.push_back(PathBuf::from(chunk.clone()));
+                    dirs_nat.push_back(chunk.clone());
                     dirs_attr = dirs_nat.clone();
-                    Some(x)
+                    (Some(x), Some(module_file_mod), None)
                 }
                 (Err(ref e1), Err(ref e2))
                     if multimodule_mode
@@ -221,38 +373,21 @@ pub(crate) fn expand_impl<R: Resolver>(
                             )
                         ) =>
                 {
-                    None
+                    (None, None, unresolved(UnresolvedReason::NotFound))
                 }
                 (Err(e), _) => return Err(e),
                 (_, Err(e)) => return Err(e),
             };
-            let some_span = item_mod.span();
-            let throwaway_group = proc_macro2::Group::new(proc_macro2::Delimiter::Bracket, Default::default());
-            // I'm not sure what to do with spans of newly generated tokens.
-            let some_delim_span = throwaway_group.delim_span();
             let cfg = if multimodule_mode && !accumulated_cfgs.is_empty() {
-                let tokens_inside_any : TokenStream = Punctuated::<_,Token![,]>::from_iter(
-                    accumulated_cfgs
-                        .iter()
-                        .map(|x| x.clone()),
-                ).into_token_stream();
-                let tokens_inside_not : TokenStream = syn::Meta::List(
-                    MetaList {
-                        path: simple_path(some_span, "any"),
-                        delimiter: syn::MacroDelimiter::Paren(syn::token::Paren { span: some_delim_span }),
-                        tokens: tokens_inside_any,
-                    },
-                ).into_token_stream();
-                Some(syn::Meta::List(MetaList {
-                    path: simple_path(some_span, "not"),
-                    delimiter: syn::MacroDelimiter::Paren(syn::token::Paren { span: some_delim_span }),
-                    tokens: tokens_inside_not,
-                }))
+                let any = cfg_combinator(some_span, "any", accumulated_cfgs.iter().cloned());
+                Some(cfg_combinator(some_span, "not", [any]))
             } else {
                 None
             };
             expansion_candidates.push(ExpandedModuleInfo {
                 result,
+                file,
+                unresolved,
                 dirs_attr,
                 dirs_nat,
                 injected_cfg: cfg,
@@ -264,42 +399,102 @@ pub(crate) fn expand_impl<R: Resolver>(
             assert_eq!(expansion_candidates.len(), 1);
         }
 
-        for ExpandedModuleInfo {
+        /// Expanded module in multimodule mode, before its `#[cfg]` is injected
+        struct ModuleVariant {
+            /// Conditions of candidates that expanded to this module, `None` means unconditional
+            cfgs: Vec<Option<syn::Meta>>,
+            cfg_position: usize,
+            module: syn::ItemMod,
+        }
+        let mut variants: Vec<ModuleVariant> = Vec::new();
+        let merge_identical = multimodule_mode && resolver.merge_identical_modules();
+
+        'candidates_loop: for ExpandedModuleInfo {
             result,
+            file,
+            unresolved,
             dirs_attr,
             dirs_nat,
             injected_cfg: cfg,
         } in expansion_candidates.into_iter()
         {
+            let mut node = ModuleTree {
+                name: id.clone(),
+                vis: item_mod.vis.clone(),
+                file,
+                cfg: module_cfgs.iter().cloned().chain(cfg.clone()).collect(),
+                children: vec![],
+            };
             if let Some(inner) = result {
                 let mut inner_items = inner.items;
 
-                let mut attrs_copy = attrs.clone();
-
-                let some_span = item_mod.span();
-                let throwaway_group = proc_macro2::Group::new(proc_macro2::Delimiter::Bracket, Default::default());
-                let some_delim_span = throwaway_group.delim_span();
-                if let Some(cfg) = cfg {
-                    if multimodule_mode {
-                        attrs_copy.push(syn::Attribute {
-                            // unsure what to do with the spans of the newly generated tokens
-                            // just plugging whatever matches the signature and what I have found the first.
-                            pound_token: syn::token::Pound { spans: [some_span] },
-                            style: syn::AttrStyle::Outer,
-                            bracket_token: syn::token::Bracket { span: some_delim_span },
-                            meta : syn::Meta::List(syn::MetaList {
-                                path: simple_path(some_span, "cfg"),
-                                delimiter: syn::MacroDelimiter::Paren(syn::token::Paren { span: some_delim_span }),
-                                tokens: cfg.into_token_stream(),
-                            }),
-                        })
+                let mut inner_attrs = Vec::with_capacity(inner.attrs.len());
+                let mut inner_cfg_attrs = Vec::new();
+                let mut inner_path_attrs = Vec::new();
+                attrs::read_inner_attributes(
+                    inner.attrs,
+                    &mut inner_path_attrs,
+                    &mut inner_attrs,
+                    &mut inner_cfg_attrs,
+                )
+                .map_err(|e| err(ErrorCase::AttrParseError(e)))?;
+                if !inner_path_attrs.is_empty() {
+                    return Err(err(ErrorCase::PathAttributeInModuleFile {
+                        path: node.file.clone().unwrap_or_default(),
+                    }));
+                }
+
+                // Inner `#![cfg]`s disable the module just like outer ones
+                let mut inner_cfgs = Vec::with_capacity(inner_cfg_attrs.len());
+                for cfg in inner_cfg_attrs {
+                    let cfg: syn::Meta =
+                        syn::parse2(cfg).map_err(|e| err(ErrorCase::SynParseError(e)))?;
+                    let query = CfgQuery {
+                        cfg: cfg.clone(),
+                        module: mod_syn_path.clone(),
+                        file: node.file.clone(),
+                        origin: CfgOrigin::InnerCfg,
+                        candidate_path: None,
+                    };
+                    if !resolver
+                        .check_cfg_query(query)
+                        .map_err(|e| err(ErrorCase::ErrorFromCallback(e)))?
+                    {
+                        diagnostics.skipped.push((mod_syn_path.clone(), cfg.clone()));
+                        if !multimodule_mode {
+                            // Left unexpanded like modules with false outer `#[cfg]`,
+                            // with the `cfg` moved to the `mod` item to keep the output equivalent
+                            item_mod.attrs.push(cfg_attribute(some_span, cfg));
+                            continue 'items_loop;
+                        }
+                        continue 'candidates_loop;
                     }
+                    inner_cfgs.push(cfg);
                 }
+                node.cfg.extend(inner_cfgs.iter().cloned());
 
-                for attr in inner.attrs {
-                    attrs_copy.push(attr);
+                let mut attrs_copy = if multimodule_mode {
+                    // Keep the gates, so that output is equivalent to the input for every configuration
+                    item_mod
+                        .attrs
+                        .iter()
+                        .filter(|x| x.path().is_ident("cfg"))
+                        .cloned()
+                        .chain(attrs.iter().cloned())
+                        .collect()
+                } else {
+                    attrs.clone()
+                };
+                if multimodule_mode {
+                    // Converted to outer gates
+                    attrs_copy.extend(inner_cfgs.into_iter().map(|cfg| cfg_attribute(some_span, cfg)));
                 }
 
+                // Injected `#[cfg]` goes here, after the attributes of the `mod` item
+                let cfg_position = attrs_copy.len();
+
+                attrs_copy.extend(inner_attrs);
+
                 //dbg!(&inner_stack, &dirs_nat, &dirs_attr);
 
                 // recursive call to process nested modules
@@ -311,10 +506,31 @@ pub(crate) fn expand_impl<R: Resolver>(
                     dirs_nat,
                     dirs_attr,
                     multimodule_mode,
+                    false,
+                    node.file.as_deref(),
+                    &mut node.children,
+                    diagnostics,
                 )?;
+                #[cfg(feature = "span-locations")]
+                if !multimodule_mode {
+                    if let Some(file) = &node.file {
+                        diagnostics.declarations.push(ExpandedDeclaration {
+                            parent: current_file.map(Path::to_owned),
+                            semicolon: semicolon_after_module_declaration.span.start(),
+                            path_attrs: item_mod
+                                .attrs
+                                .iter()
+                                .filter(|x| attrs::is_path_attribute(x))
+                                .map(|x| (x.pound_token.span.start(), x.bracket_token.span.close().end()))
+                                .collect(),
+                            file: file.clone(),
+                        });
+                    }
+                }
+                tree.push(node);
 
                 let vis = item_mod.vis.clone();
-                let mod_token = item_mod.mod_token.clone();
+                let mod_token = item_mod.mod_token;
                 let new_mod = syn::ItemMod {
                     attrs: attrs_copy,
                     vis,
@@ -331,18 +547,463 @@ pub(crate) fn expand_impl<R: Resolver>(
                 if !multimodule_mode {
                     *item = syn::Item::Mod(new_mod);
                     continue 'items_loop;
+                }
+                let merge_with = if merge_identical {
+                    variants.iter_mut().find(|x| x.module == new_mod)
                 } else {
-                    multimodule_tmp_container.push(syn::Item::Mod(new_mod));
+                    None
+                };
+                match merge_with {
+                    Some(variant) => variant.cfgs.push(cfg),
+                    None => variants.push(ModuleVariant {
+                        cfgs: vec![cfg],
+                        cfg_position,
+                        module: new_mod,
+                    }),
+                }
+            } else {
+                if !multimodule_mode {
+                    // Left as `mod name;`. In multimodule mode it is dropped from the output instead.
+                    tree.push(node);
+                }
+                if let Some(unresolved) = unresolved {
+                    if resolver.error_on_unresolved_modules() {
+                        return Err(err(ErrorCase::UnresolvedModule {
+                            candidates: unresolved.candidates,
+                            reason: unresolved.reason,
+                        }));
+                    }
+                    diagnostics.unresolved.push(unresolved);
                 }
             }
         }
+
+        for ModuleVariant {
+            cfgs,
+            cfg_position,
+            module: mut new_mod,
+        } in variants
+        {
+            let cfg = if cfgs.iter().any(Option::is_none) {
+                None
+            } else if cfgs.len() == 1 {
+                cfgs.into_iter().next().unwrap()
+            } else {
+                Some(cfg_combinator(some_span, "any", cfgs.into_iter().flatten()))
+            };
+            if let Some(cfg) = cfg {
+                new_mod.attrs.insert(cfg_position, cfg_attribute(some_span, cfg));
+            }
+            multimodule_tmp_container.push(syn::Item::Mod(new_mod));
+        }
     } // end loop
     if multimodule_mode {
         *content = multimodule_tmp_container;
     }
+    diagnostics.macro_scope.truncate(macro_scope_len);
+    Ok(())
+}
+
+/// The same as rustc's default `recursion_limit`
+const MACRO_RECURSION_LIMIT: usize = 128;
+
+/// Replace item macros with what [`Resolver::expand_item_macro`] returns for them,
+/// or with expansion of local `macro_rules!` macros, recursively.
+/// Returns length of [`Diagnostics::macro_scope`] at the position of each resulting item.
+#[allow(clippy::too_many_arguments)]
+fn expand_item_macros<R: Resolver>(
+    content: &mut Vec<syn::Item>,
+    resolver: &mut R,
+    modules_stack: &Vector<syn::Ident>,
+    multimodule_mode: bool,
+    current_file: Option<&Path>,
+    diagnostics: &mut Diagnostics,
+    depth: usize,
+) -> Result<Vec<usize>, Error> {
+    if !content.iter().any(|x| matches!(x, syn::Item::Macro(..))) {
+        return Ok(vec![diagnostics.macro_scope.len(); content.len()]);
+    }
+    let module = syn::Path {
+        leading_colon: None,
+        segments: modules_stack.iter().cloned().map(syn::PathSegment::from).collect(),
+    };
+    let err = |c| Error {
+        module: module.clone(),
+        inner: c,
+    };
+    let evaluate_macro_rules = resolver.evaluate_macro_rules();
+    let mut expanded = Vec::with_capacity(content.len());
+    let mut scopes = Vec::with_capacity(content.len());
+    for item in std::mem::take(content) {
+        let syn::Item::Macro(item_macro) = &item else {
+            expanded.push(item);
+            scopes.push(diagnostics.macro_scope.len());
+            continue;
+        };
+        let enabled = cfgs_enabled(item_macro.attrs.iter().map(|x| x.meta.clone()), &mut |cfg, origin| {
+            resolver.check_cfg_query(CfgQuery {
+                cfg,
+                module: module.clone(),
+                file: current_file.map(ToOwned::to_owned),
+                origin,
+                candidate_path: None,
+            })
+        })
+        .map_err(err)?;
+        if !enabled {
+            // Left as is, like the compiler would not expand it. Disabled definitions do not shadow enabled ones.
+            expanded.push(item);
+            scopes.push(diagnostics.macro_scope.len());
+            continue;
+        }
+        if evaluate_macro_rules {
+            diagnostics.macro_scope.extend(MacroRules::parse(item_macro));
+        }
+        let mut replacement = resolver
+            .expand_item_macro(module.clone(), item_macro)
+            .map_err(|e| err(ErrorCase::ErrorFromCallback(e)))?;
+        if replacement.is_none() && evaluate_macro_rules && item_macro.ident.is_none() {
+            replacement = evaluate_local_macro(&item_macro.mac, &module, diagnostics);
+        }
+        match replacement {
+            None => {
+                expanded.push(item);
+                scopes.push(diagnostics.macro_scope.len());
+            }
+            Some(mut items) => {
+                if depth >= MACRO_RECURSION_LIMIT {
+                    return Err(err(ErrorCase::MacroRecursionLimit {
+                        limit: MACRO_RECURSION_LIMIT,
+                    }));
+                }
+                scopes.extend(expand_item_macros(
+                    &mut items,
+                    resolver,
+                    modules_stack,
+                    multimodule_mode,
+                    current_file,
+                    diagnostics,
+                    depth + 1,
+                )?);
+                if multimodule_mode {
+                    // Keep the gates, so that output is equivalent to the input for every configuration
+                    let gates: Vec<&syn::Attribute> = item_macro.attrs.iter().filter(|x| is_gate(x)).collect();
+                    for item in &mut items {
+                        if let Some(attrs) = cfg_pass::item_attrs(item) {
+                            attrs.splice(0..0, gates.iter().map(|x| (*x).clone()));
+                        }
+                    }
+                }
+                expanded.extend(items);
+            }
+        }
+    }
+    *content = expanded;
+    Ok(scopes)
+}
+
+/// Evaluate `#[cfg]`s among attributes of an item and `cfg`s inside its `#[cfg_attr]`s whose conditions are true.
+/// Conditions of `#[cfg_attr]`s without `cfg` inside are not evaluated.
+fn cfgs_enabled(
+    metas: impl IntoIterator<Item = syn::Meta>,
+    check: &mut dyn FnMut(syn::Meta, CfgOrigin) -> Result<bool, UserError>,
+) -> Result<bool, ErrorCase> {
+    for meta in metas {
+        if meta.path().is_ident("cfg") {
+            let syn::Meta::List(list) = &meta else {
+                return Err(ErrorCase::AttrParseError(AttrParseError::MalformedCfg));
+            };
+            let cfg = syn::parse2(list.tokens.clone()).map_err(ErrorCase::SynParseError)?;
+            if !check(cfg, CfgOrigin::ItemCfg).map_err(ErrorCase::ErrorFromCallback)? {
+                return Ok(false);
+            }
+        } else if meta.path().is_ident("cfg_attr") {
+            let syn::Meta::List(list) = &meta else {
+                return Err(ErrorCase::AttrParseError(AttrParseError::CfgAttrNotRoundGroup));
+            };
+            let nested = list
+                .parse_args_with(Punctuated::<syn::Meta, Token![,]>::parse_terminated)
+                .map_err(|_| ErrorCase::AttrParseError(AttrParseError::MalformedCfg))?;
+            let mut nested = nested.into_iter();
+            let Some(condition) = nested.next() else {
+                return Err(ErrorCase::AttrParseError(AttrParseError::MalformedCfg));
+            };
+            let nested: Vec<syn::Meta> = nested
+                .filter(|x| x.path().is_ident("cfg") || x.path().is_ident("cfg_attr"))
+                .collect();
+            if !nested.is_empty()
+                && check(condition, CfgOrigin::CfgAttr).map_err(ErrorCase::ErrorFromCallback)?
+                && !cfgs_enabled(nested, check)?
+            {
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Whether the attribute is `#[cfg]` or a `#[cfg_attr]` that may expand to one
+fn is_gate(attr: &syn::Attribute) -> bool {
+    attr.path().is_ident("cfg")
+        || attr.path().is_ident("cfg_attr")
+            && attr
+                .parse_args_with(Punctuated::<syn::Meta, Token![,]>::parse_terminated)
+                .is_ok_and(|x| x.iter().skip(1).any(|x| x.path().is_ident("cfg") || x.path().is_ident("cfg_attr")))
+}
+
+/// Expand invocation of a `macro_rules!` macro from [`Diagnostics::macro_scope`], if it declares modules
+fn evaluate_local_macro(
+    mac: &syn::Macro,
+    module: &syn::Path,
+    diagnostics: &mut Diagnostics,
+) -> Option<Vec<syn::Item>> {
+    let name = mac.path.get_ident()?;
+    let definition = diagnostics.macro_scope.iter().rev().find(|x| x.name == *name)?;
+    match definition.expand(mac) {
+        Ok(items) if has_file_modules(&items) => Some(items),
+        Ok(_) => None,
+        Err(reason) => {
+            diagnostics.unexpanded_macros.push(UnexpandedMacro {
+                module: module.clone(),
+                name: name.clone(),
+                reason,
+            });
+            None
+        }
+    }
+}
+
+/// Synthesize `#[cfg(...)]` attribute
+fn cfg_attribute(some_span: proc_macro2::Span, cfg: syn::Meta) -> syn::Attribute {
+    let throwaway_group = proc_macro2::Group::new(proc_macro2::Delimiter::Bracket, Default::default());
+    let some_delim_span = throwaway_group.delim_span();
+    syn::Attribute {
+        // unsure what to do with the spans of the newly generated tokens
+        // just plugging whatever matches the signature and what I have found the first.
+        pound_token: syn::token::Pound { spans: [some_span] },
+        style: syn::AttrStyle::Outer,
+        bracket_token: syn::token::Bracket { span: some_delim_span },
+        meta : syn::Meta::List(syn::MetaList {
+            path: simple_path(some_span, "cfg"),
+            delimiter: syn::MacroDelimiter::Paren(syn::token::Paren { span: some_delim_span }),
+            tokens: cfg.into_token_stream(),
+        }),
+    }
+}
+
+/// Expand modules declared inside `mod name { ... }`.
+///
+/// Like in rustc, nested modules are looked up in the `name` subdirectory of the directory
+/// for natural lookup of the current file (which includes the file's stem for non-`mod.rs` files),
+/// or in the directory specified by `#[path]` attribute of the inline module.
+/// `#[path]` attributes of nested modules are relative to the same directory.
+#[allow(clippy::too_many_arguments)]
+fn expand_inline_module<R: Resolver>(
+    item_mod: &mut syn::ItemMod,
+    resolver: &mut R,
+    modules_stack: &Vector<syn::Ident>,
+    dirs_nat: &Vector<PathBuf>,
+    dirs_attr: &Vector<PathBuf>,
+    multimodule_mode: bool,
+    in_block: bool,
+    current_file: Option<&Path>,
+    tree: &mut Vec<ModuleTree>,
+    diagnostics: &mut Diagnostics,
+) -> Result<(), Error> {
+    let mut inner_stack = modules_stack.clone();
+    inner_stack.push_back(item_mod.ident.clone());
+    let mod_syn_path = syn::Path {
+        leading_colon: None,
+        segments: inner_stack.iter().cloned().map(syn::PathSegment::from).collect(),
+    };
+    let err = |c| Error {
+        module: mod_syn_path.clone(),
+        inner: c,
+    };
+
+    let mut path_attrs: Vec<(PathBuf, Option<TokenStream>)> = Vec::new();
+    let mut cfg_attrs: Vec<TokenStream> = Vec::new();
+    attrs::read_inner_attributes(
+        item_mod.attrs.clone(),
+        &mut path_attrs,
+        &mut Vec::new(),
+        &mut cfg_attrs,
+    )
+    .map_err(|e| err(ErrorCase::AttrParseError(e)))?;
+
+    let mut node = ModuleTree {
+        name: item_mod.ident.clone(),
+        vis: item_mod.vis.clone(),
+        file: None,
+        cfg: Vec::with_capacity(cfg_attrs.len()),
+        children: vec![],
+    };
+    let (_, items) = item_mod.content.as_mut().expect("inline module has content");
+
+    // `cfg`s only matter if there is something to load, so don't bother resolver otherwise
+    let needs_expansion = has_file_modules(items);
+    for cfg in cfg_attrs {
+        let cfg: syn::Meta = syn::parse2(cfg).map_err(|e| err(ErrorCase::SynParseError(e)))?;
+        node.cfg.push(cfg.clone());
+        if !needs_expansion {
+            continue;
+        }
+        let query = CfgQuery {
+            cfg: cfg.clone(),
+            module: mod_syn_path.clone(),
+            file: current_file.map(ToOwned::to_owned),
+            origin: CfgOrigin::Cfg,
+            candidate_path: None,
+        };
+        if !resolver
+            .check_cfg_query(query)
+            .map_err(|e| err(ErrorCase::ErrorFromCallback(e)))?
+        {
+            // Left as is, like the compiler would not look inside it
+            diagnostics.skipped.push((mod_syn_path, cfg));
+            return Ok(());
+        }
+    }
+    if !needs_expansion {
+        tree.push(node);
+        return Ok(());
+    }
+
+    let mut dirs;
+    match path_attrs.into_iter().find(|(_, condition)| condition.is_none()) {
+        // Relative to the directory of the current file, without the stem of a non-`mod.rs` file
+        Some((path, _)) => {
+            dirs = dirs_attr.clone();
+            dirs.push_back(path);
+        }
+        None => {
+            dirs = dirs_nat.clone();
+            dirs.push_back(PathBuf::from(item_mod.ident.unraw().to_string()));
+        }
+    }
+
+    expand_impl(
+        items,
+        resolver,
+        inner_stack,
+        dirs.clone(),
+        dirs,
+        multimodule_mode,
+        in_block,
+        current_file,
+        &mut node.children,
+        diagnostics,
+    )?;
+    tree.push(node);
     Ok(())
 }
 
+/// Expands modules declared in blocks (function bodies, `const` initializers, etc.) of an item.
+///
+/// Like in rustc, such modules need a `#[path]` attribute, which is relative to the directory
+/// of the current file (or of the enclosing inline module). Inline modules in blocks
+/// are subdirectories of that directory, even in non-`mod.rs` files.
+struct BlockModules<'a, R> {
+    resolver: &'a mut R,
+    modules_stack: &'a Vector<syn::Ident>,
+    /// Directory for `#[path]` attributes of the current module
+    dirs: &'a Vector<PathBuf>,
+    multimodule_mode: bool,
+    current_file: Option<&'a Path>,
+    tree: &'a mut Vec<ModuleTree>,
+    diagnostics: &'a mut Diagnostics,
+    error: Option<Error>,
+}
+
+impl<'a, R: Resolver> VisitMut for BlockModules<'a, R> {
+    fn visit_block_mut(&mut self, block: &mut syn::Block) {
+        let stmts = std::mem::take(&mut block.stmts);
+        block.stmts.reserve(stmts.len());
+        for mut stmt in stmts {
+            if self.error.is_some() {
+                block.stmts.push(stmt);
+                continue;
+            }
+            let syn::Stmt::Item(item @ syn::Item::Mod(..)) = stmt else {
+                self.visit_stmt_mut(&mut stmt);
+                block.stmts.push(stmt);
+                continue;
+            };
+            let mut items = vec![item];
+            let ret = expand_impl(
+                &mut items,
+                self.resolver,
+                self.modules_stack.clone(),
+                self.dirs.clone(),
+                self.dirs.clone(),
+                self.multimodule_mode,
+                true,
+                self.current_file,
+                self.tree,
+                self.diagnostics,
+            );
+            if let Err(e) = ret {
+                self.error = Some(e);
+            }
+            block.stmts.extend(items.into_iter().map(syn::Stmt::Item));
+        }
+    }
+
+    fn visit_item_mod_mut(&mut self, _: &mut syn::ItemMod) {
+        // Modules are handled by `expand_impl`, including those inside blocks
+    }
+}
+
+/// Whether there are `mod name;` items, possibly inside inline modules or blocks,
+/// or item macros that may expand to them
+fn has_file_modules(items: &[syn::Item]) -> bool {
+    struct Finder(bool);
+    impl<'ast> syn::visit::Visit<'ast> for Finder {
+        fn visit_item_macro(&mut self, i: &'ast syn::ItemMacro) {
+            if !i.mac.path.is_ident("macro_rules") {
+                self.0 = true;
+            }
+        }
+
+        fn visit_item_mod(&mut self, i: &'ast syn::ItemMod) {
+            if i.content.is_none() {
+                self.0 = true;
+            } else if !self.0 {
+                syn::visit::visit_item_mod(self, i);
+            }
+        }
+    }
+    let mut finder = Finder(false);
+    for item in items {
+        syn::visit::Visit::visit_item(&mut finder, item);
+    }
+    finder.0
+}
+
+/// Get the module's code from the resolver and parse it if needed
+fn load<R: Resolver>(resolver: &mut R, module: &syn::Path, path: PathBuf) -> Result<Option<syn::File>, Error> {
+    match resolver.resolve_source(module.clone(), path.clone())? {
+        Some(source) => source.parse(module.clone(), &path).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Information about unresolved module for the case when resolver returned `Ok(None)`
+fn declined(
+    result: &Option<syn::File>,
+    module: &syn::Path,
+    candidates: Vec<PathBuf>,
+) -> Option<UnresolvedModule> {
+    if result.is_some() {
+        return None;
+    }
+    Some(UnresolvedModule {
+        module: module.clone(),
+        candidates,
+        reason: UnresolvedReason::Declined,
+    })
+}
+
 fn simple_path(span: proc_macro2::Span, name: &'static str) -> syn::Path {
     syn::Path {
         leading_colon: None,
@@ -355,3 +1016,28 @@ fn simple_path(span: proc_macro2::Span, name: &'static str) -> syn::Path {
         )]),
     }
 }
+
+/// `name(args...)`, e.g. `any(a, b)`
+fn cfg_combinator(
+    span: proc_macro2::Span,
+    name: &'static str,
+    args: impl IntoIterator<Item = syn::Meta>,
+) -> syn::Meta {
+    let throwaway_group = proc_macro2::Group::new(proc_macro2::Delimiter::Bracket, Default::default());
+    // I'm not sure what to do with spans of newly generated tokens.
+    let some_delim_span = throwaway_group.delim_span();
+    syn::Meta::List(MetaList {
+        path: simple_path(span, name),
+        delimiter: syn::MacroDelimiter::Paren(syn::token::Paren { span: some_delim_span }),
+        tokens: Punctuated::<_, Token![,]>::from_iter(args).into_token_stream(),
+    })
+}
+
+/// `not(x)` for a single condition, `not(any(x, y, ...))` otherwise
+fn none_of(span: proc_macro2::Span, cfgs: &[syn::Meta]) -> syn::Meta {
+    let inner = match cfgs {
+        [x] => x.clone(),
+        _ => cfg_combinator(span, "any", cfgs.iter().cloned()),
+    };
+    cfg_combinator(span, "not", [inner])
+}
@@ -0,0 +1,466 @@
+#[cfg(feature = "span-locations")]
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "span-locations")]
+use crate::splice::splice_files;
+use crate::{
+    expand_impl::Diagnostics, expand_impl_root, CfgQuery, Error, ErrorCase, LoadedFiles,
+    ModuleTree, Resolver, UnexpandedMacro, UnresolvedModule, UserError,
+};
+
+/// Source of file contents for [`Expander`].
+///
+/// Implemented for [`StdFileSystem`] and for closures `FnMut(&Path) -> std::io::Result<String>`.
+pub trait FileSystem {
+    /// Read the whole file. Errors are reported as [`ErrorCase::FailedToOpenFile`].
+    fn read_to_string(&mut self, path: &Path) -> std::io::Result<String>;
+}
+
+/// [`FileSystem`] that reads files using `std::fs`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdFileSystem;
+
+impl FileSystem for StdFileSystem {
+    fn read_to_string(&mut self, path: &Path) -> std::io::Result<String> {
+        std::fs::read_to_string(path)
+    }
+}
+
+impl<F: FnMut(&Path) -> std::io::Result<String>> FileSystem for F {
+    fn read_to_string(&mut self, path: &Path) -> std::io::Result<String> {
+        self(path)
+    }
+}
+
+/// Result of [`Expander::expand_path`]
+#[derive(Debug, Clone)]
+pub struct Expansion {
+    /// The source code with modules expanded
+    pub file: syn::File,
+    /// Files that were read or probed
+    pub files: LoadedFiles,
+    /// Hierarchy of modules, like returned by [`module_tree`](crate::module_tree)
+    pub modules: Vec<ModuleTree>,
+    /// Modules that were left unexpanded because no file was loaded for them
+    pub unresolved: Vec<UnresolvedModule>,
+    /// Local macros that were left as is, see [`Expander::evaluate_macro_rules`]
+    pub unexpanded_macros: Vec<UnexpandedMacro>,
+    /// Files that were embedded as verbatim tokens, see [`Expander::tolerant_parsing`]
+    pub unparsed_files: Vec<UnparsedFile>,
+}
+
+/// Result of [`Expander::expand_path_to_text`]
+#[cfg(feature = "span-locations")]
+#[derive(Debug, Clone)]
+pub struct TextExpansion {
+    /// The source text with `mod name;` declarations replaced by file contents
+    pub text: String,
+    /// Files that were read or probed
+    pub files: LoadedFiles,
+    /// Hierarchy of modules, like returned by [`module_tree`](crate::module_tree)
+    pub modules: Vec<ModuleTree>,
+    /// Modules that were left unexpanded because no file was loaded for them
+    pub unresolved: Vec<UnresolvedModule>,
+    /// Files that could not be parsed, see [`Expander::tolerant_parsing`]
+    pub unparsed_files: Vec<UnparsedFile>,
+}
+
+/// File that `syn` could not parse, embedded as [`syn::Item::Verbatim`] by [`Expander::tolerant_parsing`]
+#[derive(Debug, Clone)]
+pub struct UnparsedFile {
+    /// Path of the file, as passed to [`FileSystem::read_to_string`]
+    pub path: PathBuf,
+    /// The parse error, its span has the line and column
+    pub error: syn::Error,
+}
+
+/// Builder-style configuration for loading crate source code.
+///
+/// Defaults are the same as for [`read_full_crate_source_code`](crate::read_full_crate_source_code) with `|_|Ok(false)`:
+/// all `cfg`s are false, files are read using `std::fs`, no duplicate modules, no depth limit.
+///
+/// Example:
+///
+/// ```
+/// # fn main() -> Result<(), syn_file_expand::Error> {
+/// let mut input_file = std::path::PathBuf::new();
+/// # input_file.push(env!("CARGO_MANIFEST_DIR"));
+/// input_file.push("src");
+/// input_file.push("lib.rs");
+/// let expansion = syn_file_expand::Expander::new()
+///     .cfg_provider(|_cfg| Ok(true))
+///     .multimodule(true)
+///     .max_depth(16)
+///     .expand_path(input_file)?;
+/// assert!(expansion.modules.iter().any(|x| x.name == "expand_impl"));
+/// assert!(expansion.files.read.len() > 1);
+/// #   Ok(())
+/// # }
+/// ```
+pub struct Expander<'a> {
+    cfg_provider: Box<dyn FnMut(CfgQuery) -> Result<bool, UserError> + 'a>,
+    filesystem: Box<dyn FileSystem + 'a>,
+    multimodule: bool,
+    max_depth: Option<usize>,
+    error_on_unresolved_modules: bool,
+    first_path_wins: bool,
+    merge_identical_modules: bool,
+    strip_cfg: bool,
+    normalize_cfg_attr: bool,
+    item_macro_handler: Option<Box<ItemMacroHandler<'a>>>,
+    evaluate_macro_rules: bool,
+    tolerant_parsing: bool,
+}
+
+/// Callback for [`Expander::item_macro_handler`]
+type ItemMacroHandler<'a> =
+    dyn FnMut(syn::Path, &syn::ItemMacro) -> Result<Option<Vec<syn::Item>>, UserError> + 'a;
+
+impl<'a> Default for Expander<'a> {
+    fn default() -> Self {
+        Expander {
+            cfg_provider: Box::new(|_| Ok(false)),
+            filesystem: Box::new(StdFileSystem),
+            multimodule: false,
+            max_depth: None,
+            error_on_unresolved_modules: false,
+            first_path_wins: false,
+            merge_identical_modules: false,
+            strip_cfg: false,
+            normalize_cfg_attr: false,
+            item_macro_handler: None,
+            evaluate_macro_rules: false,
+            tolerant_parsing: false,
+        }
+    }
+}
+
+impl<'a> Expander<'a> {
+    /// Create expander with default settings
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set callback to evaluate `cfg` predicates, see [`Resolver::check_cfg`]
+    pub fn cfg_provider(
+        mut self,
+        mut cfg_provider: impl FnMut(syn::Meta) -> Result<bool, UserError> + 'a,
+    ) -> Self {
+        self.cfg_provider = Box::new(move |query: CfgQuery| cfg_provider(query.cfg));
+        self
+    }
+
+    /// Set callback to evaluate `cfg` predicates that also receives information about the module,
+    /// see [`Resolver::check_cfg_query`]. Replaces [`Expander::cfg_provider`].
+    pub fn cfg_query_provider(
+        mut self,
+        cfg_provider: impl FnMut(CfgQuery) -> Result<bool, UserError> + 'a,
+    ) -> Self {
+        self.cfg_provider = Box::new(cfg_provider);
+        self
+    }
+
+    /// Set where to read files from. Paths given to it are paths of the root file and
+    /// of module files joined to the root file's directory.
+    pub fn filesystem(mut self, filesystem: impl FileSystem + 'a) -> Self {
+        self.filesystem = Box::new(filesystem);
+        self
+    }
+
+    /// Load all variants of modules, see [`Resolver::allow_duplicate_modules_and_convert_cfg`]
+    pub fn multimodule(mut self, multimodule: bool) -> Self {
+        self.multimodule = multimodule;
+        self
+    }
+
+    /// Fail with [`ErrorCase::MaxDepthExceeded`] if file-based modules are nested deeper than that,
+    /// e.g. because of `#[path]` pointing to the file itself. See [`Resolver::max_depth`].
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// See [`Resolver::error_on_unresolved_modules`]
+    pub fn error_on_unresolved_modules(mut self, error_on_unresolved_modules: bool) -> Self {
+        self.error_on_unresolved_modules = error_on_unresolved_modules;
+        self
+    }
+
+    /// See [`Resolver::first_path_attribute_wins`]
+    pub fn first_path_wins(mut self, first_path_wins: bool) -> Self {
+        self.first_path_wins = first_path_wins;
+        self
+    }
+
+    /// See [`Resolver::merge_identical_modules`]
+    pub fn merge_identical_modules(mut self, merge_identical_modules: bool) -> Self {
+        self.merge_identical_modules = merge_identical_modules;
+        self
+    }
+
+    /// After expanding modules, also evaluate `#[cfg]` and `#[cfg_attr]` on other items using the same
+    /// callback, see [`strip_cfg`](crate::strip_cfg)
+    pub fn strip_cfg(mut self, strip_cfg: bool) -> Self {
+        self.strip_cfg = strip_cfg;
+        self
+    }
+
+    /// After expanding modules, also replace `#[cfg_attr]`s everywhere with attributes they resolve to,
+    /// using the same callback, see [`normalize_cfg_attr`](crate::normalize_cfg_attr).
+    /// Implied by [`Expander::strip_cfg`].
+    pub fn normalize_cfg_attr(mut self, normalize_cfg_attr: bool) -> Self {
+        self.normalize_cfg_attr = normalize_cfg_attr;
+        self
+    }
+
+    /// Set callback to replace item-position macro invocations with items, see [`Resolver::expand_item_macro`]
+    pub fn item_macro_handler(
+        mut self,
+        item_macro_handler: impl FnMut(syn::Path, &syn::ItemMacro) -> Result<Option<Vec<syn::Item>>, UserError> + 'a,
+    ) -> Self {
+        self.item_macro_handler = Some(Box::new(item_macro_handler));
+        self
+    }
+
+    /// See [`Resolver::evaluate_macro_rules`]
+    pub fn evaluate_macro_rules(mut self, evaluate_macro_rules: bool) -> Self {
+        self.evaluate_macro_rules = evaluate_macro_rules;
+        self
+    }
+
+    /// Embed files that can be tokenized, but not parsed by `syn` (e.g. because of new syntax)
+    /// as a single [`syn::Item::Verbatim`] instead of failing with [`ErrorCase::FailedToParseFile`].
+    /// Modules declared in such files are not expanded. Such files are listed in [`Expansion::unparsed_files`].
+    pub fn tolerant_parsing(mut self, tolerant_parsing: bool) -> Self {
+        self.tolerant_parsing = tolerant_parsing;
+        self
+    }
+
+    /// Read the root file of a crate (e.g. `src/lib.rs`) and expand its modules.
+    ///
+    /// **Security**: the same considerations as for [`read_full_crate_source_code`](crate::read_full_crate_source_code) apply,
+    /// unless [`Expander::filesystem`] and [`Expander::max_depth`] are used to restrict that.
+    pub fn expand_path(&mut self, path: impl AsRef<Path>) -> Result<Expansion, Error> {
+        let path = path.as_ref();
+        let mut files = LoadedFiles::default();
+        let mut modules = Vec::new();
+        let mut diagnostics = Diagnostics::default();
+        let mut unparsed_files = Vec::new();
+        let mut resolver = self.resolver(path, &mut files, Some(&mut unparsed_files));
+        let mut file = resolver.read_root()?;
+        expand_impl_root(&mut file.items, &mut resolver, &mut modules, &mut diagnostics)?;
+        if self.strip_cfg {
+            crate::strip_cfg(&mut file, &mut self.cfg_provider)?;
+        } else if self.normalize_cfg_attr {
+            crate::normalize_cfg_attr(&mut file, &mut self.cfg_provider)?;
+        }
+        Ok(Expansion {
+            file,
+            files,
+            modules,
+            unresolved: diagnostics.unresolved,
+            unexpanded_macros: diagnostics.unexpanded_macros,
+            unparsed_files,
+        })
+    }
+
+    /// Like [`Expander::expand_path`], but splice source texts instead of printing the `syn` tree,
+    /// preserving comments, blank lines and formatting.
+    ///
+    /// Each expanded `mod name;` is replaced by `mod name { ... }` with the file text, indented by four more spaces
+    /// than the declaration line (except for continuation lines of multi-line literals). `#[path]` attributes
+    /// of the declaration are removed, other attributes (including `#[cfg]`) and inner attributes of the file are kept.
+    ///
+    /// [`Expander::multimodule`], [`Expander::strip_cfg`], [`Expander::normalize_cfg_attr`],
+    /// [`Expander::item_macro_handler`] and [`Expander::evaluate_macro_rules`] are ignored:
+    /// only modules declared literally in the source text are expanded, once.
+    ///
+    /// Requires the `span-locations` feature.
+    #[cfg(feature = "span-locations")]
+    pub fn expand_path_to_text(&mut self, path: impl AsRef<Path>) -> Result<TextExpansion, Error> {
+        let path = path.as_ref();
+        let mut texts = HashMap::new();
+        let mut files = LoadedFiles::default();
+        let mut modules = Vec::new();
+        let mut diagnostics = Diagnostics::default();
+        let mut unparsed_files = Vec::new();
+        let mut resolver = self.resolver(path, &mut files, Some(&mut unparsed_files));
+        resolver.allow_duplicate_modules_and_convert_cfgs = false;
+        resolver.merge_identical_modules = false;
+        resolver.item_macro_handler = None;
+        resolver.evaluate_macro_rules = false;
+        resolver.texts = Some(&mut texts);
+        let mut file = resolver.read_root()?;
+        expand_impl_root(&mut file.items, &mut resolver, &mut modules, &mut diagnostics)?;
+        Ok(TextExpansion {
+            text: splice_files(&texts, path, &diagnostics.declarations),
+            files,
+            modules,
+            unresolved: diagnostics.unresolved,
+            unparsed_files,
+        })
+    }
+
+    /// [`FsResolver`] for the crate with the given root file, configured from this expander.
+    /// `unparsed_files` is only filled in if [`Expander::tolerant_parsing`] is on, otherwise unparsable files are errors.
+    pub(crate) fn resolver<'b>(
+        &'b mut self,
+        root: &'b Path,
+        files: &'b mut LoadedFiles,
+        unparsed_files: Option<&'b mut Vec<UnparsedFile>>,
+    ) -> FsResolver<'b> {
+        FsResolver {
+            cfg_attr_path_handler: &mut *self.cfg_provider,
+            root,
+            allow_duplicate_modules_and_convert_cfgs: self.multimodule,
+            files,
+            fs: &mut *self.filesystem,
+            max_depth: self.max_depth,
+            error_on_unresolved_modules: self.error_on_unresolved_modules,
+            first_path_wins: self.first_path_wins,
+            merge_identical_modules: self.merge_identical_modules,
+            item_macro_handler: match &mut self.item_macro_handler {
+                Some(x) => Some(&mut **x),
+                None => None,
+            },
+            evaluate_macro_rules: self.evaluate_macro_rules,
+            unparsed: unparsed_files.filter(|_| self.tolerant_parsing),
+            #[cfg(feature = "span-locations")]
+            texts: None,
+        }
+    }
+}
+
+/// Parse file, falling back to verbatim tokens if `unparsed` is given to record the error to
+fn parse_file(
+    source: &str,
+    path: &Path,
+    unparsed: Option<&mut Vec<UnparsedFile>>,
+) -> syn::Result<syn::File> {
+    let error = match syn::parse_file(source) {
+        Ok(x) => return Ok(x),
+        Err(e) => e,
+    };
+    let Some(unparsed) = unparsed else { return Err(error) };
+    let source = source.strip_prefix('\u{feff}').unwrap_or(source);
+    let tokens: proc_macro2::TokenStream = source.parse().map_err(|_| error.clone())?;
+    unparsed.push(UnparsedFile {
+        path: path.to_owned(),
+        error,
+    });
+    Ok(syn::File {
+        shebang: None,
+        attrs: Vec::new(),
+        items: vec![syn::Item::Verbatim(tokens)],
+    })
+}
+
+/// [`Resolver`] that reads files relative to the directory of the root file, created by [`Expander::resolver`]
+pub(crate) struct FsResolver<'a> {
+    pub cfg_attr_path_handler: &'a mut dyn FnMut(CfgQuery) -> Result<bool, UserError>,
+    pub root: &'a Path,
+    pub allow_duplicate_modules_and_convert_cfgs: bool,
+    pub files: &'a mut LoadedFiles,
+    pub fs: &'a mut dyn FileSystem,
+    pub max_depth: Option<usize>,
+    pub error_on_unresolved_modules: bool,
+    pub first_path_wins: bool,
+    pub merge_identical_modules: bool,
+    pub item_macro_handler: Option<&'a mut ItemMacroHandler<'a>>,
+    pub evaluate_macro_rules: bool,
+    /// Where to record unparsable files in tolerant mode, `None` to fail on them
+    pub unparsed: Option<&'a mut Vec<UnparsedFile>>,
+    /// Where to record texts of read files, for [`Expander::expand_path_to_text`]
+    #[cfg(feature = "span-locations")]
+    pub texts: Option<&'a mut HashMap<PathBuf, String>>,
+}
+
+impl<'a> FsResolver<'a> {
+    /// Read and parse the root file
+    pub fn read_root(&mut self) -> Result<syn::File, Error> {
+        let root_module = syn::Path {
+            leading_colon: None,
+            segments: syn::punctuated::Punctuated::new(),
+        };
+        self.read(root_module, self.root.to_owned())
+    }
+
+    fn read(&mut self, module_name: syn::Path, path: PathBuf) -> Result<syn::File, Error> {
+        let module_source = match self.fs.read_to_string(&path) {
+            Ok(x) => {
+                self.files.read.push(path.clone());
+                x
+            }
+            Err(e) => {
+                self.files.missing.push(path.clone());
+                return Err(Error {
+                    module: module_name,
+                    inner: ErrorCase::FailedToOpenFile { path, e },
+                });
+            }
+        };
+        let ret = parse_file(&module_source, &path, self.unparsed.as_deref_mut()).map_err(|e| Error {
+            module: module_name,
+            inner: ErrorCase::parse_error(&path, e),
+        });
+        #[cfg(feature = "span-locations")]
+        if let Some(texts) = &mut self.texts {
+            texts.insert(path, module_source);
+        }
+        ret
+    }
+}
+
+impl<'a> Resolver for FsResolver<'a> {
+    fn resolve(
+        &mut self,
+        module_name: syn::Path,
+        path_relative_to_crate_root: PathBuf,
+    ) -> Result<Option<syn::File>, Error> {
+        let path = match self.root.parent() {
+            Some(parent_dir) => parent_dir.join(&path_relative_to_crate_root),
+            None => path_relative_to_crate_root,
+        };
+        self.read(module_name, path).map(Some)
+    }
+
+    fn check_cfg_query(&mut self, query: CfgQuery) -> Result<bool, UserError> {
+        (self.cfg_attr_path_handler)(query)
+    }
+
+    fn allow_duplicate_modules_and_convert_cfg(&mut self) -> bool {
+        self.allow_duplicate_modules_and_convert_cfgs
+    }
+
+    fn error_on_unresolved_modules(&mut self) -> bool {
+        self.error_on_unresolved_modules
+    }
+
+    fn max_depth(&mut self) -> Option<usize> {
+        self.max_depth
+    }
+
+    fn first_path_attribute_wins(&mut self) -> bool {
+        self.first_path_wins
+    }
+
+    fn merge_identical_modules(&mut self) -> bool {
+        self.merge_identical_modules
+    }
+
+    fn expand_item_macro(
+        &mut self,
+        module: syn::Path,
+        item_macro: &syn::ItemMacro,
+    ) -> Result<Option<Vec<syn::Item>>, UserError> {
+        match &mut self.item_macro_handler {
+            Some(handler) => handler(module, item_macro),
+            None => Ok(None),
+        }
+    }
+
+    fn evaluate_macro_rules(&mut self) -> bool {
+        self.evaluate_macro_rules
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use syn::{punctuated::Punctuated, Meta, Token};
+
+use crate::{
+    cfg_list::normalize_predicate,
+    delegate::{Delegating, Overrides},
+    expand_impl_root, CfgQuery, Error, ErrorCase, FileSystem,
+    ModuleTree, Resolver, UserError,
+};
+
+/// Result of [`module_layouts`](crate::module_layouts)
+#[derive(Debug)]
+pub struct ModuleLayouts {
+    /// Atomic `cfg` predicates (like `unix` or `feature = "std"`) that module-level
+    /// `#[cfg]` and `#[cfg_attr(..., path = ...)]` attributes depend on, in order of appearance
+    pub atoms: Vec<String>,
+    /// Distinct module trees, in order of increasing number of enabled atoms of their representative configuration
+    pub layouts: Vec<ModuleLayout>,
+    /// Configurations for which module expansion failed, e.g. because of a missing file
+    pub invalid: Vec<InvalidConfiguration>,
+}
+
+/// One of distinct module trees reported in [`ModuleLayouts`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleLayout {
+    /// Representative configuration: atoms that are enabled, all others are disabled.
+    /// This is the first configuration with the least number of enabled atoms that produces this tree.
+    pub enabled: Vec<String>,
+    /// Number of configurations (out of `2^atoms.len()`) that produce this tree
+    pub configurations: usize,
+    /// The module tree, like returned by [`module_tree`](crate::module_tree)
+    pub modules: Vec<ModuleTree>,
+}
+
+/// Configuration for which module expansion failed, as reported in [`ModuleLayouts`]
+#[derive(Debug)]
+pub struct InvalidConfiguration {
+    /// Atoms that are enabled, all others are disabled
+    pub enabled: Vec<String>,
+    /// The error
+    pub error: Error,
+}
+
+impl ModuleLayouts {
+    /// Maximum number of atoms, as all `2^atoms` configurations are tried.
+    /// [`ErrorCase::TooManyCfgAtoms`] is returned if there are more.
+    pub const MAX_ATOMS: usize = 12;
+}
+
+fn nested(meta: &Meta) -> Option<Punctuated<Meta, Token![,]>> {
+    let Meta::List(list) = meta else { return None };
+    if !["all", "any", "not"].iter().any(|x| list.path.is_ident(x)) {
+        return None;
+    }
+    list.parse_args_with(Punctuated::parse_terminated).ok()
+}
+
+fn collect_atoms(meta: &Meta, atoms: &mut Vec<String>) {
+    match nested(meta) {
+        Some(args) => args.iter().for_each(|x| collect_atoms(x, atoms)),
+        None => {
+            let atom = normalize_predicate(meta);
+            if !atoms.contains(&atom) {
+                atoms.push(atom);
+            }
+        }
+    }
+}
+
+fn eval(meta: &Meta, enabled: &[String]) -> bool {
+    match nested(meta) {
+        Some(args) if meta.path().is_ident("all") => args.iter().all(|x| eval(x, enabled)),
+        Some(args) if meta.path().is_ident("any") => args.iter().any(|x| eval(x, enabled)),
+        Some(args) => !args.iter().any(|x| eval(x, enabled)),
+        None => enabled.contains(&normalize_predicate(meta)),
+    }
+}
+
+enum Mode<'a> {
+    /// Load all module variants, recording atoms of all queried predicates
+    Discover(&'a mut Vec<String>),
+    /// Evaluate predicates according to the given enabled atoms
+    Evaluate(&'a [String]),
+}
+
+impl<'a, R: Resolver> Overrides<R> for Mode<'a> {
+    fn check_cfg_query(&mut self, _inner: &mut R, query: CfgQuery) -> Result<bool, UserError> {
+        Ok(match self {
+            Mode::Discover(atoms) => {
+                collect_atoms(&query.cfg, atoms);
+                true
+            }
+            Mode::Evaluate(enabled) => eval(&query.cfg, enabled),
+        })
+    }
+
+    fn allow_duplicate_modules_and_convert_cfg(&mut self, _inner: &mut R) -> bool {
+        matches!(self, Mode::Discover(..))
+    }
+}
+
+pub(crate) fn module_layouts<R: Resolver>(
+    content: &syn::File,
+    resolver: &mut R,
+) -> Result<ModuleLayouts, Error> {
+    let mut atoms = Vec::new();
+    expand_impl_root(
+        &mut content.items.clone(),
+        &mut Delegating {
+            inner: resolver,
+            overrides: Mode::Discover(&mut atoms),
+        },
+        &mut Vec::new(),
+        &mut Default::default(),
+    )?;
+    if atoms.len() > ModuleLayouts::MAX_ATOMS {
+        return Err(Error {
+            module: syn::Path {
+                leading_colon: None,
+                segments: Punctuated::new(),
+            },
+            inner: ErrorCase::TooManyCfgAtoms {
+                atoms: atoms.len(),
+                max_atoms: ModuleLayouts::MAX_ATOMS,
+            },
+        });
+    }
+
+    let mut masks: Vec<u32> = (0..1u32 << atoms.len()).collect();
+    masks.sort_by_key(|x| x.count_ones());
+
+    let mut layouts: Vec<ModuleLayout> = Vec::new();
+    let mut invalid = Vec::new();
+    for mask in masks {
+        let enabled: Vec<String> = atoms
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| mask & (1 << i) != 0)
+            .map(|(_, x)| x.clone())
+            .collect();
+        let mut modules = Vec::new();
+        let ret = expand_impl_root(
+            &mut content.items.clone(),
+            &mut Delegating {
+                inner: &mut *resolver,
+                overrides: Mode::Evaluate(&enabled),
+            },
+            &mut modules,
+            &mut Default::default(),
+        );
+        match ret {
+            Err(error) => invalid.push(InvalidConfiguration { enabled, error }),
+            Ok(()) => match layouts.iter_mut().find(|x| x.modules == modules) {
+                Some(x) => x.configurations += 1,
+                None => layouts.push(ModuleLayout {
+                    enabled,
+                    configurations: 1,
+                    modules,
+                }),
+            },
+        }
+    }
+
+    Ok(ModuleLayouts {
+        atoms,
+        layouts,
+        invalid,
+    })
+}
+
+/// [`FileSystem`] that reads each file only once, as the same files are loaded for each configuration
+#[derive(Default)]
+pub(crate) struct CachingFileSystem(HashMap<PathBuf, Result<String, std::io::ErrorKind>>);
+
+impl FileSystem for CachingFileSystem {
+    fn read_to_string(&mut self, path: &Path) -> std::io::Result<String> {
+        self.0
+            .entry(path.to_owned())
+            .or_insert_with(|| std::fs::read_to_string(path).map_err(|e| e.kind()))
+            .clone()
+            .map_err(std::io::Error::from)
+    }
+}
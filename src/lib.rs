@@ -9,6 +9,7 @@ pub extern crate proc_macro2;
 pub extern crate syn;
 
 use im_rc::Vector;
+use itertools::Itertools;
 
 /// Generic error type to report from callbacks
 pub type UserError = Box<dyn std::error::Error + Send + Sync + 'static>;
@@ -33,9 +34,10 @@ pub enum AttrParseError {
     MalformedCfg,
 }
 
-/// Specifics of error when expanding a particular module
+/// Specifics of error when expanding a particular module. New variants may be added in minor versions.
 #[allow(missing_docs)]
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum ErrorCase {
     #[error("Cannot open file {path}: {e}")]
     FailedToOpenFile { path: PathBuf, e: std::io::Error },
@@ -47,8 +49,117 @@ pub enum ErrorCase {
     AttrParseError(AttrParseError),
     #[error("syn parsing error: {0}")]
     SynParseError(syn::parse::Error),
+    #[error("Cannot parse {path}{}: {e}", PositionForDisplay(*line, *column))]
+    FailedToParseFile {
+        path: PathBuf,
+        /// 1-based, 0 without the `span-locations` feature
+        line: usize,
+        /// 1-based, in characters, 0 without the `span-locations` feature
+        column: usize,
+        e: syn::parse::Error,
+    },
     #[error("Error from callback: {0}")]
     ErrorFromCallback(UserError),
+    #[error("Module is unresolved ({reason}), tried {}", PathsForDisplay(candidates))]
+    UnresolvedModule {
+        candidates: Vec<PathBuf>,
+        reason: UnresolvedReason,
+    },
+    #[error("Maximum module nesting depth ({max_depth}) exceeded, maybe #[path] points to a file itself")]
+    MaxDepthExceeded { max_depth: usize },
+    #[error("#![path] or #![cfg_attr(..., path = ...)] in {path} has no effect, path attributes must be on the `mod` item")]
+    PathAttributeInModuleFile { path: PathBuf },
+    #[error("Non-inline module inside a block (e.g. a function body) needs a #[path] attribute")]
+    ModuleInBlockWithoutPath,
+    #[error("Item macro expansion is nested deeper than {limit} levels")]
+    MacroRecursionLimit { limit: usize },
+    #[error("Module layout depends on {atoms} cfg atoms, at most {max_atoms} are supported")]
+    TooManyCfgAtoms { atoms: usize, max_atoms: usize },
+}
+
+impl ErrorCase {
+    /// [`ErrorCase::FailedToParseFile`] with position taken from the error's span
+    pub(crate) fn parse_error(path: &std::path::Path, e: syn::parse::Error) -> ErrorCase {
+        let (line, column) = span_position(e.span());
+        ErrorCase::FailedToParseFile {
+            path: path.to_owned(),
+            line,
+            column,
+            e,
+        }
+    }
+
+    /// Entry points that predate [`ErrorCase::FailedToParseFile`] keep reporting [`ErrorCase::SynParseError`]
+    pub(crate) fn into_legacy(self) -> ErrorCase {
+        match self {
+            ErrorCase::FailedToParseFile { e, .. } => ErrorCase::SynParseError(e),
+            x => x,
+        }
+    }
+}
+
+/// Why a module was left unexpanded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnresolvedReason {
+    /// [`Resolver::resolve`] returned `Ok(None)` for all candidate files
+    Declined,
+    /// None of candidate files could be opened. This is only tolerated in multimodule mode,
+    /// otherwise [`ErrorCase::FailedToOpenFile`] is returned.
+    NotFound,
+}
+
+impl std::fmt::Display for UnresolvedReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnresolvedReason::Declined => "declined by resolver".fmt(f),
+            UnresolvedReason::NotFound => "no file found".fmt(f),
+        }
+    }
+}
+
+/// Module that was left as `mod name;` (or dropped in multimodule mode) because there was no file to expand it from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnresolvedModule {
+    /// Full path of the module
+    pub module: syn::Path,
+    /// Files that were tried, relative to the root module, as passed to [`Resolver::resolve`]
+    pub candidates: Vec<PathBuf>,
+    /// Why the module was not resolved
+    pub reason: UnresolvedReason,
+}
+
+/// Why a local `macro_rules!` macro invocation was left as is by [`Resolver::evaluate_macro_rules`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum UnexpandedMacroReason {
+    /// The definition uses something that is not supported, like `$x:expr` fragments
+    UnsupportedDefinition,
+    /// None of the rules matched the invocation
+    NoMatchingRule,
+    /// The expansion is not a sequence of items
+    InvalidExpansion,
+}
+
+impl std::fmt::Display for UnexpandedMacroReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnexpandedMacroReason::UnsupportedDefinition => "unsupported macro definition".fmt(f),
+            UnexpandedMacroReason::NoMatchingRule => "no rule matched".fmt(f),
+            UnexpandedMacroReason::InvalidExpansion => "expansion is not a list of items".fmt(f),
+        }
+    }
+}
+
+/// Invocation of a local `macro_rules!` macro that the built-in evaluator could not expand,
+/// see [`Resolver::evaluate_macro_rules`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnexpandedMacro {
+    /// Full path of the module containing the invocation
+    pub module: syn::Path,
+    /// Name of the macro
+    pub name: syn::Ident,
+    /// Why it was left as is
+    pub reason: UnexpandedMacroReason,
 }
 
 /// Main error type that is returned from functions of this crate, as well as from some user callbacks.
@@ -61,6 +172,37 @@ pub struct Error {
     pub inner: ErrorCase,
 }
 
+struct PathsForDisplay<'a>(&'a [PathBuf]);
+impl<'a> std::fmt::Display for PathsForDisplay<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        itertools::join(self.0.iter().map(|x| x.display()), ", ").fmt(f)
+    }
+}
+
+/// `:line:column`, or nothing if the position is unknown
+struct PositionForDisplay(usize, usize);
+impl std::fmt::Display for PositionForDisplay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0 == 0 {
+            return Ok(());
+        }
+        write!(f, ":{}:{}", self.0, self.1)
+    }
+}
+
+/// 1-based line and column (in characters) of the start of the span.
+/// `proc-macro2` tracks them only with its `span-locations` feature, `(0, 0)` is returned otherwise.
+#[cfg(feature = "span-locations")]
+pub(crate) fn span_position(span: proc_macro2::Span) -> (usize, usize) {
+    let start = span.start();
+    (start.line, start.column + 1)
+}
+
+#[cfg(not(feature = "span-locations"))]
+pub(crate) fn span_position(_span: proc_macro2::Span) -> (usize, usize) {
+    (0, 0)
+}
+
 struct PathForDisplay<'a>(&'a syn::Path);
 impl<'a> std::fmt::Display for PathForDisplay<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -73,7 +215,7 @@ impl<'a> std::fmt::Display for PathForDisplay<'a> {
 pub trait Resolver {
     /// Called each time a non-inline module is encountered that needs to be expanded.
     /// `module_name` is full path from the specified file root to the module being expanded,
-    /// to use for error messages.
+    /// to use for error messages. Raw identifiers stay as written (`r#type`).
     ///
     /// `relative_path` is pathname of a file the module should be expanded from,
     /// relative to root module you are expanding. You will probably need to join this path
@@ -83,21 +225,188 @@ pub trait Resolver {
     /// It is error to return more than one Ok(Some) for one module, unless `allow_duplicate_modules_and_convert_cfg` is set to true.
     ///
     /// Retuning Ok(None) for all candidate files leaves the module unexpanded in [`expand_modules_into_inline_modules`]'s output.
+    /// Such modules are reported by [`expand_modules_into_inline_modules_ex`], see also [`Resolver::error_on_unresolved_modules`].
+    ///
+    /// To return code as text or tokens and let the library parse it, also implement [`Resolver::resolve_source`].
     fn resolve(
         &mut self,
         module_name: syn::Path,
         path_relative_to_crate_root: PathBuf,
     ) -> Result<Option<syn::File>, Error>;
 
+    /// The same as [`Resolver::resolve`], but the code may also be returned as text or tokens, to be parsed by the library.
+    /// Parse errors are then reported as [`ErrorCase::FailedToParseFile`] with the file path and position.
+    /// This is what is actually called by the library. Default implementation forwards to [`Resolver::resolve`].
+    ///
+    /// When overriding this, `resolve` can be implemented by parsing the result with [`ModuleSource::parse`].
+    fn resolve_source(
+        &mut self,
+        module_name: syn::Path,
+        path_relative_to_crate_root: PathBuf,
+    ) -> Result<Option<ModuleSource>, Error> {
+        Ok(self
+            .resolve(module_name, path_relative_to_crate_root)?
+            .map(ModuleSource::Parsed))
+    }
+
     /// When `#[cfg(mymeta)] mod ...;` or `#[cfg_attr(mymeta,path=...)]` is encountered, this function is called
     /// and you should provide answer whether this cfg should be considered true or false.
-    fn check_cfg(&mut self, cfg: syn::Meta) -> Result<bool, UserError>;
+    ///
+    /// Either this or [`Resolver::check_cfg_query`] (if you need to know which module the `cfg` belongs to)
+    /// should be implemented. Default implementation fails with [`ErrorCase::ErrorFromCallback`].
+    fn check_cfg(&mut self, cfg: syn::Meta) -> Result<bool, UserError> {
+        let _ = cfg;
+        Err("neither Resolver::check_cfg nor Resolver::check_cfg_query is implemented".into())
+    }
+
+    /// The same as [`Resolver::check_cfg`], but with additional information about where the `cfg` comes from.
+    /// This is what is actually called by the library. Default implementation forwards `query.cfg` to [`Resolver::check_cfg`].
+    fn check_cfg_query(&mut self, query: CfgQuery) -> Result<bool, UserError> {
+        self.check_cfg(query.cfg)
+    }
 
     /// Include all the modules, possibly duplicating them.
-    /// `#[cfg_attr(...,path)] mod ...;` are converted to `#[cfg(...)] mod .. {}`
+    /// `#[cfg_attr(...,path)] mod ...;` are converted to `#[cfg(...)] mod .. {}`,
+    /// `#[cfg(...)]` attributes of modules are kept and `#![cfg(...)]` of module files become outer ones,
+    /// so the output is equivalent to the input for every configuration.
     /// 
     /// `check_cfg` is still called, but it is not a problem to return `true` unconditionally.
     fn allow_duplicate_modules_and_convert_cfg(&mut self) -> bool { false }
+
+    /// When a module has multiple `#[path]` and `#[cfg_attr(..., path = ...)]` attributes, use the first
+    /// one that is in effect and ignore the rest, like the compiler does, instead of failing with
+    /// [`ErrorCase::MultipleExplicitPathsSpecifiedForOneModule`].
+    ///
+    /// In multimodule mode, conditions of module variants are made mutually exclusive:
+    /// `#[cfg_attr(b, path = "b.rs")] #[cfg_attr(c, path = "c.rs")] mod m;` becomes
+    /// `#[cfg(b)] mod m {..}`, `#[cfg(all(c, not(b)))] mod m {..}` and `#[cfg(not(any(b, c)))] mod m {..}`,
+    /// so that exactly one of them is present in every configuration.
+    fn first_path_attribute_wins(&mut self) -> bool { false }
+
+    /// In multimodule mode, merge variants of a module that turned out identical after expansion
+    /// (e.g. platform stubs copied or symlinked to several files) into one module
+    /// gated by `#[cfg(any(...))]` of their conditions.
+    ///
+    /// [`module_tree`] still reports each variant separately.
+    fn merge_identical_modules(&mut self) -> bool { false }
+
+    /// Fail with [`ErrorCase::UnresolvedModule`] instead of silently leaving modules unexpanded
+    /// when no candidate file was loaded for them.
+    fn error_on_unresolved_modules(&mut self) -> bool { false }
+
+    /// Maximum nesting depth of non-inline modules, top-level modules of the root file have depth 1.
+    /// Expanding a module nested deeper fails with [`ErrorCase::MaxDepthExceeded`].
+    fn max_depth(&mut self) -> Option<usize> { None }
+
+    /// Called for each item-position macro invocation, like `platform_mods! { linux, macos }`,
+    /// in the root file, loaded files and inline modules. `macro_rules!` definitions are passed here as well.
+    /// `module` is full path of the module containing the invocation.
+    ///
+    /// Returning `Ok(Some(items))` replaces the invocation with `items`, which are then processed
+    /// like other items: macros among them are passed here again and `mod name;` are expanded.
+    /// Invocations with false `#[cfg]` are not passed here. In multimodule mode, `#[cfg]`s of the invocation
+    /// are copied to each of the `items`.
+    /// Default implementation leaves all macros as is.
+    fn expand_item_macro(
+        &mut self,
+        module: syn::Path,
+        item_macro: &syn::ItemMacro,
+    ) -> Result<Option<Vec<syn::Item>>, UserError> {
+        let _ = (module, item_macro);
+        Ok(None)
+    }
+
+    /// Evaluate invocations of simple `macro_rules!` macros defined earlier in the same module or in its parents,
+    /// so that modules they declare get expanded. It is tried when [`Resolver::expand_item_macro`] returns `Ok(None)`.
+    ///
+    /// Supported are literal tokens, `$x:ident`, `$x:path` (without generics), `$x:tt`, `$x:literal` and `$x:vis`
+    /// fragments and repetitions, matched greedily. `#[macro_use]` and `#[macro_export]` are not taken into account.
+    /// Definitions and invocations with false `#[cfg]` are skipped.
+    /// Invocations that do not expand to modules are left as is. Macros that cannot be evaluated are left as is as well,
+    /// [`Expander`] reports them in [`Expansion::unexpanded_macros`].
+    fn evaluate_macro_rules(&mut self) -> bool { false }
+}
+
+/// Code of a module, as returned by [`Resolver::resolve_source`]
+#[derive(Debug, Clone)]
+pub enum ModuleSource {
+    /// Already parsed code
+    Parsed(syn::File),
+    /// Source text, like contents of a file
+    Text(String),
+    /// Tokens, e.g. generated by some tool
+    Tokens(proc_macro2::TokenStream),
+}
+
+impl ModuleSource {
+    /// Parse the code, reporting errors as [`ErrorCase::FailedToParseFile`] with the given `path`
+    pub fn parse(self, module: syn::Path, path: &std::path::Path) -> Result<syn::File, Error> {
+        let ret = match self {
+            ModuleSource::Parsed(x) => return Ok(x),
+            ModuleSource::Text(x) => syn::parse_file(&x),
+            ModuleSource::Tokens(x) => syn::parse2(x),
+        };
+        ret.map_err(|e| Error {
+            module,
+            inner: ErrorCase::parse_error(path, e),
+        })
+    }
+}
+
+impl From<syn::File> for ModuleSource {
+    fn from(x: syn::File) -> Self {
+        ModuleSource::Parsed(x)
+    }
+}
+
+impl From<String> for ModuleSource {
+    fn from(x: String) -> Self {
+        ModuleSource::Text(x)
+    }
+}
+
+impl From<proc_macro2::TokenStream> for ModuleSource {
+    fn from(x: proc_macro2::TokenStream) -> Self {
+        ModuleSource::Tokens(x)
+    }
+}
+
+/// Kind of attribute a [`CfgQuery`] comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum CfgOrigin {
+    /// `#[cfg(...)] mod name;`, or `#[cfg(...)] mod name { ... }` with non-inline modules inside
+    Cfg,
+    /// `#[cfg_attr(..., path = "...")] mod name;`
+    CfgAttrPath,
+    /// `#![cfg(...)]` at the top of a module file. [`CfgQuery::file`] is that file.
+    InnerCfg,
+    /// `#[cfg(...)]` on an item, field, statement, etc., evaluated by [`strip_cfg`],
+    /// or on an item macro invocation before it is expanded
+    ItemCfg,
+    /// `#[cfg_attr(..., attrs...)]` evaluated by [`strip_cfg`] or [`normalize_cfg_attr`],
+    /// or `#[cfg_attr(..., cfg(...))]` on an item macro invocation
+    CfgAttr,
+}
+
+/// Argument of [`Resolver::check_cfg_query`]
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CfgQuery {
+    /// The predicate to evaluate
+    pub cfg: syn::Meta,
+    /// Full path of the module the attribute is attached to.
+    /// For [`CfgOrigin::ItemCfg`] and [`CfgOrigin::CfgAttr`], the module the attribute is in.
+    pub module: syn::Path,
+    /// File containing the `mod` item, relative to root module, as passed to [`Resolver::resolve`].
+    /// `None` for the root file and for attributes evaluated by [`strip_cfg`] or [`normalize_cfg_attr`].
+    /// For [`CfgOrigin::InnerCfg`], the module file itself.
+    pub file: Option<PathBuf>,
+    /// Kind of attribute the predicate comes from
+    pub origin: CfgOrigin,
+    /// For [`CfgOrigin::CfgAttrPath`], file that would be loaded if the predicate is true,
+    /// relative to root module.
+    pub candidate_path: Option<PathBuf>,
 }
 
 /// Helper struct to define `Resolver` implementations using closures.
@@ -164,18 +473,319 @@ where
 pub fn expand_modules_into_inline_modules<R: Resolver>(
     content: &mut syn::File,
     resolver: &mut R,
+) -> Result<(), Error> {
+    expand_impl_root(
+        &mut content.items,
+        resolver,
+        &mut Vec::new(),
+        &mut Default::default(),
+    )
+}
+
+/// The same as [`expand_modules_into_inline_modules`], but also returns list of modules
+/// that were left unexpanded because no file was loaded for them.
+pub fn expand_modules_into_inline_modules_ex<R: Resolver>(
+    content: &mut syn::File,
+    resolver: &mut R,
+) -> Result<Vec<UnresolvedModule>, Error> {
+    let mut diagnostics = expand_impl::Diagnostics::default();
+    expand_impl_root(
+        &mut content.items,
+        resolver,
+        &mut Vec::new(),
+        &mut diagnostics,
+    )?;
+    Ok(diagnostics.unresolved)
+}
+
+fn expand_impl_root<R: Resolver>(
+    items: &mut Vec<syn::Item>,
+    resolver: &mut R,
+    tree: &mut Vec<ModuleTree>,
+    diagnostics: &mut expand_impl::Diagnostics,
 ) -> Result<(), Error> {
     let dirs = Vector::new();
     let mutltimodule_mode = resolver.allow_duplicate_modules_and_convert_cfg();
     expand_impl::expand_impl(
-        &mut content.items,
+        items,
         resolver,
         Vector::new(),
         dirs.clone(),
         dirs,
         mutltimodule_mode,
+        false,
+        None,
+        tree,
+        diagnostics,
+    )
+}
+
+/// Evaluate `#[cfg]` and `#[cfg_attr]` attributes everywhere in already expanded code, like the compiler does.
+///
+/// Items, associated items, fields, enum variants, match arms, statements, struct literal fields, function parameters,
+/// generic parameters and elements of arrays, tuples and argument lists are removed if any of their `#[cfg]`s is false.
+/// Remaining `#[cfg]` attributes are removed. All `#[cfg_attr]`s are processed like in [`normalize_cfg_attr`].
+///
+/// `cfg_provider` is called the same way as [`Resolver::check_cfg_query`], so the same callback that was used
+/// to expand modules can be reused here.
+///
+/// Example:
+///
+/// ```
+/// # fn main() -> Result<(), syn_file_expand::Error> {
+/// use quote::ToTokens;
+/// let mut ast: syn::File = syn::parse2(quote::quote! {
+///     #[cfg_attr(feature = "serde", derive(Serialize))]
+///     struct Foo {
+///         #[cfg(unix)]
+///         fd: i32,
+///     }
+/// }).unwrap();
+/// syn_file_expand::strip_cfg(&mut ast, |query| Ok(query.cfg.to_token_stream().to_string() != "unix"))?;
+///
+/// let expected: syn::File = syn::parse2(quote::quote! {
+///     #[derive(Serialize)]
+///     struct Foo {
+///     }
+/// }).unwrap();
+/// assert_eq!(ast, expected);
+/// #   Ok(())
+/// # }
+/// ```
+pub fn strip_cfg(
+    content: &mut syn::File,
+    mut cfg_provider: impl FnMut(CfgQuery) -> Result<bool, UserError>,
+) -> Result<(), Error> {
+    let mut pass = cfg_pass::CfgPass::new(&mut cfg_provider, true);
+    syn::visit_mut::VisitMut::visit_file_mut(&mut pass, content);
+    pass.error.map_or(Ok(()), Err)
+}
+
+/// Replace every `#[cfg_attr(pred, attrs...)]` in already expanded code (on items, fields, expressions, etc.)
+/// with `attrs` if `pred` is true or remove it otherwise, so that tools which do not understand `cfg_attr`
+/// see the effective attributes. Nested `cfg_attr`s are also processed.
+///
+/// Unlike [`strip_cfg`], `#[cfg]` attributes and the code they gate are left as is.
+///
+/// Example:
+///
+/// ```
+/// # fn main() -> Result<(), syn_file_expand::Error> {
+/// use quote::ToTokens;
+/// let mut ast: syn::File = syn::parse2(quote::quote! {
+///     #[cfg_attr(feature = "serde", derive(Serialize))]
+///     struct Foo {
+///         #[cfg_attr(feature = "serde", serde(skip))]
+///         #[cfg(unix)]
+///         fd: i32,
+///     }
+/// }).unwrap();
+/// syn_file_expand::normalize_cfg_attr(&mut ast, |query| Ok(query.cfg.to_token_stream().to_string() != "unix"))?;
+///
+/// let expected: syn::File = syn::parse2(quote::quote! {
+///     #[derive(Serialize)]
+///     struct Foo {
+///         #[serde(skip)]
+///         #[cfg(unix)]
+///         fd: i32,
+///     }
+/// }).unwrap();
+/// assert_eq!(ast, expected);
+/// #   Ok(())
+/// # }
+/// ```
+pub fn normalize_cfg_attr(
+    content: &mut syn::File,
+    mut cfg_provider: impl FnMut(CfgQuery) -> Result<bool, UserError>,
+) -> Result<(), Error> {
+    let mut pass = cfg_pass::CfgPass::new(&mut cfg_provider, false);
+    syn::visit_mut::VisitMut::visit_file_mut(&mut pass, content);
+    pass.error.map_or(Ok(()), Err)
+}
+
+/// Information about one module (inline or loaded from a file), as reported by [`module_tree`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleTree {
+    /// Name of the module, as written in the source (e.g. `r#type` for a module stored in `type.rs`)
+    pub name: syn::Ident,
+    /// Visibility of the `mod` item
+    pub vis: syn::Visibility,
+    /// File the module was loaded from, relative to root module, as passed to [`Resolver::resolve`].
+    /// `None` for inline modules and for modules that were left unexpanded.
+    pub file: Option<PathBuf>,
+    /// Conditions that gate this module: its `#[cfg]`s that were considered true
+    /// and the condition of `#[cfg_attr(...,path=...)]` that was used to load it, if any.
+    ///
+    /// For inline modules, this is just the content of their `#[cfg]` attributes. They are only evaluated
+    /// if there are modules to load inside. Modules with a `cfg` that was evaluated to false are not listed,
+    /// whether they are inline or not.
+    pub cfg: Vec<syn::Meta>,
+    /// Modules declared inside this module
+    pub children: Vec<ModuleTree>,
+}
+
+/// Resolve modules the same way as [`expand_modules_into_inline_modules`] does, but only
+/// return the hierarchy of modules instead of the expanded code.
+///
+/// Returned vector contains top-level modules of `content`.
+/// In multimodule mode (see [`Resolver::allow_duplicate_modules_and_convert_cfg`]),
+/// there may be multiple entries with the same name, but different `cfg`s.
+///
+/// Example:
+///
+/// ```
+/// # fn main() -> Result<(), syn_file_expand::Error> {
+/// let ast: syn::File = syn::parse2(quote::quote! {
+///     pub mod inner_module;
+/// }).unwrap();
+/// let mut resolver = syn_file_expand::ResolverHelper(
+///     |_module, path: std::path::PathBuf| Ok(if path.ends_with("inner_module.rs") {
+///         Some(syn::parse2(quote::quote! { mod nested {} }).unwrap())
+///     } else {
+///         None
+///     }),
+///     |_cfg| Ok(false),
+/// );
+/// let tree = syn_file_expand::module_tree(&ast, &mut resolver)?;
+///
+/// assert_eq!(tree.len(), 1);
+/// assert_eq!(tree[0].name, "inner_module");
+/// assert_eq!(tree[0].file, Some("inner_module.rs".into()));
+/// assert_eq!(tree[0].children[0].name, "nested");
+/// #   Ok(())
+/// # }
+/// ```
+pub fn module_tree<R: Resolver>(
+    content: &syn::File,
+    resolver: &mut R,
+) -> Result<Vec<ModuleTree>, Error> {
+    let mut tree = Vec::new();
+    expand_impl_root(
+        &mut content.items.clone(),
+        resolver,
+        &mut tree,
+        &mut Default::default(),
+    )?;
+    Ok(tree)
+}
+
+/// Kind of construct a `cfg` predicate was found in, see [`CfgLocation`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CfgKind {
+    /// `#[cfg(...)]`
+    Cfg,
+    /// Condition of `#[cfg_attr(..., ...)]`
+    CfgAttr,
+    /// `cfg!(...)` macro call
+    CfgMacro,
+}
+
+impl std::fmt::Display for CfgKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CfgKind::Cfg => "cfg".fmt(f),
+            CfgKind::CfgAttr => "cfg_attr".fmt(f),
+            CfgKind::CfgMacro => "cfg!".fmt(f),
+        }
+    }
+}
+
+/// Place where a `cfg` predicate is used
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CfgLocation {
+    /// Module containing the attribute or macro call
+    pub module: syn::Path,
+    /// File containing the predicate, relative to root module, as passed to [`Resolver::resolve`].
+    /// `None` for the root file.
+    pub file: Option<PathBuf>,
+    /// Line number in that file, starting from 1. 0 without the `span-locations` feature.
+    pub line: usize,
+    /// Whether it is `#[cfg]`, `#[cfg_attr]` or `cfg!`
+    pub kind: CfgKind,
+}
+
+/// Distinct `cfg` predicate used in a crate, as returned by [`cfg_predicates`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CfgPredicate {
+    /// Normalized predicate, e.g. `all(feature = "std", unix)`.
+    /// Spacing is canonicalized and arguments of `all` and `any` are sorted and de-duplicated.
+    pub predicate: String,
+    /// All places where the predicate is used. Its length is the usage count.
+    pub locations: Vec<CfgLocation>,
+}
+
+/// List all distinct `cfg` predicates (in `#[cfg]`, `#[cfg_attr]` and `cfg!`) used in `content`,
+/// sorted by their normalized text.
+///
+/// Modules are not loaded, use [`read_cfg_predicates`] to scan the whole crate.
+///
+/// Example:
+///
+/// ```
+/// let ast: syn::File = syn::parse2(quote::quote! {
+///     #[cfg(any(windows, unix))]
+///     mod a {
+///         #[cfg_attr(any(unix,windows), derive(Debug))]
+///         struct S;
+///         fn f() -> bool { cfg!(feature="std") }
+///     }
+/// }).unwrap();
+/// let cfgs = syn_file_expand::cfg_predicates(&ast);
+///
+/// assert_eq!(cfgs.len(), 2);
+/// assert_eq!(cfgs[0].predicate, "any(unix, windows)");
+/// assert_eq!(cfgs[0].locations.len(), 2);
+/// assert_eq!(cfgs[1].predicate, "feature = \"std\"");
+/// assert_eq!(cfgs[1].locations[0].kind, syn_file_expand::CfgKind::CfgMacro);
+/// ```
+pub fn cfg_predicates(content: &syn::File) -> Vec<CfgPredicate> {
+    let mut found = Vec::new();
+    syn::visit::Visit::visit_file(
+        &mut cfg_list::Collector {
+            file: None,
+            modules: vec![],
+            found: &mut found,
+        },
+        content,
+    );
+    cfg_list::group(found)
+}
+
+/// Filesystem-based counterpart of [`cfg_predicates`]. All variants of all modules are loaded,
+/// like in [`read_crate`], so predicates are listed regardless of which of them are true.
+///
+/// See [`read_full_crate_source_code`] documentation for security considerations.
+pub fn read_cfg_predicates(path: impl AsRef<std::path::Path>) -> Result<Vec<CfgPredicate>, Error> {
+    read_cfg_predicates_tracked(path, &mut LoadedFiles::default())
+}
+
+/// The same as [`read_cfg_predicates`], but also records every file path the loader tried to open into `files`,
+/// like [`read_full_crate_source_code_tracked`] does.
+pub fn read_cfg_predicates_tracked(
+    path: impl AsRef<std::path::Path>,
+    files: &mut LoadedFiles,
+) -> Result<Vec<CfgPredicate>, Error> {
+    let path = path.as_ref();
+    let mut expander = Expander::new().cfg_provider(|_| Ok(true)).multimodule(true);
+    let mut resolver = expander.resolver(path, files, None);
+    let mut root_source = resolver.read_root()?;
+    let mut found = Vec::new();
+    syn::visit::Visit::visit_file(
+        &mut cfg_list::Collector {
+            file: None,
+            modules: vec![],
+            found: &mut found,
+        },
+        &root_source,
+    );
+    expand_modules_into_inline_modules(
+        &mut root_source,
+        &mut delegate::Delegating {
+            inner: &mut resolver,
+            overrides: cfg_list::Collecting { found: &mut found },
+        },
     )?;
-    Ok(())
+    Ok(cfg_list::group(found))
 }
 
 /// High-level function to load full crate source code from the filesystem. Use it instead of [`read_crate`]
@@ -221,8 +831,8 @@ pub fn read_full_crate_source_code(
 /// * `allow_duplicate_modules_and_convert_cfgs`
 ///     - if `false`, act the same as [`read_full_crate_source_code`].
 ///     - if `true`, allow loading of duplicate modules (e.g. for different platforms) and preserve
-///                their cfg gates. In this mode, the most logical way to specify `cfg_attr_path_handler` is
-///                just constant `|_|Ok(true)`. This is equivalent to just using [`read_crate`] instead.
+///       their cfg gates. In this mode, the most logical way to specify `cfg_attr_path_handler` is
+///       just constant `|_|Ok(true)`. This is equivalent to just using [`read_crate`] instead.
 ///
 ///  See other info and warnings in [`read_full_crate_source_code`] documentation.
 pub fn read_full_crate_source_code_ex(
@@ -230,76 +840,229 @@ pub fn read_full_crate_source_code_ex(
     cfg_attr_path_handler: impl FnMut(syn::Meta) -> Result<bool, UserError>,
     allow_duplicate_modules_and_convert_cfgs: bool,
 ) -> Result<syn::File, Error> {
-    let path = path.as_ref();
-    let root_source = std::fs::read_to_string(path).map_err(|e| Error {
-        module: syn::Path {
-            leading_colon: None,
-            segments: syn::punctuated::Punctuated::new(),
-        },
-        inner: ErrorCase::FailedToOpenFile {
-            path: path.to_owned(),
-            e,
-        },
-    })?;
-    let mut root_source: syn::File = syn::parse_file(&root_source).map_err(|e| Error {
-        module: syn::Path {
-            leading_colon: None,
-            segments: syn::punctuated::Punctuated::new(),
-        },
-        inner: ErrorCase::SynParseError(e),
-    })?;
+    Expander::new()
+        .cfg_provider(cfg_attr_path_handler)
+        .multimodule(allow_duplicate_modules_and_convert_cfgs)
+        .expand_path(path)
+        .map(|x| x.file)
+        .map_err(legacy_error)
+}
+
+/// Paths of files that were consulted while loading a crate from the filesystem.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoadedFiles {
+    /// Files that were successfully read, in order of reading. The root file comes first.
+    pub read: Vec<PathBuf>,
+    /// Candidate files that were probed, but could not be opened,
+    /// e.g. the unused one of `name.rs` and `name/mod.rs`.
+    pub missing: Vec<PathBuf>,
+}
 
-    let parent_dir = path.parent();
+/// The same as [`read_full_crate_source_code_ex`], but also records every file path
+/// the loader tried to open into `files`.
+///
+/// `files` is filled in even if an error is returned, so it can be used to decide
+/// which files to watch for changes before trying again.
+pub fn read_full_crate_source_code_tracked(
+    path: impl AsRef<std::path::Path>,
+    cfg_attr_path_handler: impl FnMut(syn::Meta) -> Result<bool, UserError>,
+    allow_duplicate_modules_and_convert_cfgs: bool,
+    files: &mut LoadedFiles,
+) -> Result<syn::File, Error> {
+    let path = path.as_ref();
+    let mut expander = Expander::new()
+        .cfg_provider(cfg_attr_path_handler)
+        .multimodule(allow_duplicate_modules_and_convert_cfgs);
+    let mut resolver = expander.resolver(path, files, None);
+    let mut root_source = resolver.read_root().map_err(legacy_error)?;
+    expand_modules_into_inline_modules(&mut root_source, &mut resolver).map_err(legacy_error)?;
+    Ok(root_source)
+}
 
-    struct MyResolver<'a, F: FnMut(syn::Meta) -> Result<bool, UserError>> {
-        cfg_attr_path_handler: F,
-        parent_dir: Option<&'a std::path::Path>,
-        allow_duplicate_modules_and_convert_cfgs: bool,
+fn legacy_error(e: Error) -> Error {
+    Error {
+        module: e.module,
+        inner: e.inner.into_legacy(),
     }
+}
 
-    impl<'a, F: FnMut(syn::Meta) -> Result<bool, UserError>> Resolver for MyResolver<'a, F> {
-        fn resolve(
-            &mut self,
-            module_name: syn::Path,
-            path_relative_to_crate_root: PathBuf,
-        ) -> Result<Option<syn::File>, Error> {
-            let path = if let Some(parent_dir) = self.parent_dir {
-                parent_dir.join(&path_relative_to_crate_root)
-            } else {
-                path_relative_to_crate_root
-            };
-            let module_source = std::fs::read_to_string(&path).map_err(|e| Error {
-                module: module_name.clone(),
-                inner: ErrorCase::FailedToOpenFile {
-                    path: path.clone(),
-                    e,
-                },
-            })?;
-            let module_source: syn::File = syn::parse_file(&module_source).map_err(|e| Error {
-                module: module_name,
-                inner: ErrorCase::SynParseError(e),
-            })?;
-            Ok(Some(module_source))
-        }
+/// Filesystem-based counterpart of [`module_tree`]. Arguments are the same as for [`read_full_crate_source_code_ex`].
+///
+/// See [`read_full_crate_source_code`] documentation for security considerations.
+pub fn read_module_tree(
+    path: impl AsRef<std::path::Path>,
+    cfg_attr_path_handler: impl FnMut(syn::Meta) -> Result<bool, UserError>,
+    allow_duplicate_modules_and_convert_cfgs: bool,
+) -> Result<Vec<ModuleTree>, Error> {
+    Ok(Expander::new()
+        .cfg_provider(cfg_attr_path_handler)
+        .multimodule(allow_duplicate_modules_and_convert_cfgs)
+        .expand_path(path)?
+        .modules)
+}
 
-        fn check_cfg(&mut self, cfg: syn::Meta) -> Result<bool, UserError> {
-            (self.cfg_attr_path_handler)(cfg)
-        }
+/// Find out which combinations of `cfg`s change the module hierarchy of `content`.
+///
+/// All variants of modules are loaded first to gather atomic predicates (like `unix` or `feature = "std"`)
+/// used in module-level `#[cfg]` and `#[cfg_attr(..., path = ...)]` attributes. Then modules are resolved
+/// for each of `2^n` combinations of these atoms being true or false (`all`, `any` and `not` are evaluated
+/// accordingly) and distinct resulting trees are reported with a representative combination for each.
+///
+/// Predicates are evaluated by this function itself: `check_cfg` and `check_cfg_query` of `resolver` are never called
+/// and `allow_duplicate_modules_and_convert_cfg` is ignored. Other methods of `resolver` (`resolve_source`, `max_depth`,
+/// `first_path_attribute_wins`, `merge_identical_modules`, `error_on_unresolved_modules`, `expand_item_macro`
+/// and `evaluate_macro_rules`) are used as usual. `resolve_source` is called many times for the same files.
+///
+/// Fails with [`ErrorCase::TooManyCfgAtoms`] if there are more than [`ModuleLayouts::MAX_ATOMS`] atoms.
+///
+/// Example:
+///
+/// ```
+/// # fn main() -> Result<(), syn_file_expand::Error> {
+/// let ast: syn::File = syn::parse2(quote::quote! {
+///     #[cfg(unix)]
+///     mod sys;
+///     #[cfg(all(unix, not(feature = "std")))]
+///     mod nostd;
+/// }).unwrap();
+/// let mut resolver = syn_file_expand::ResolverHelper(
+///     |_module, path: std::path::PathBuf| Ok(if path.to_str() == Some("sys.rs") || path.to_str() == Some("nostd.rs") {
+///         Some(syn::parse2(quote::quote! {}).unwrap())
+///     } else {
+///         None
+///     }),
+///     |_cfg| Ok(false),
+/// );
+/// let layouts = syn_file_expand::module_layouts(&ast, &mut resolver)?;
+///
+/// assert_eq!(layouts.atoms, ["unix", "feature = \"std\""]);
+/// assert_eq!(layouts.layouts.len(), 3);
+/// assert!(layouts.layouts[0].enabled.is_empty());
+/// assert_eq!(layouts.layouts[1].enabled, ["unix"]);
+/// assert_eq!(layouts.layouts[2].modules.len(), 1);
+/// #   Ok(())
+/// # }
+/// ```
+pub fn module_layouts<R: Resolver>(
+    content: &syn::File,
+    resolver: &mut R,
+) -> Result<ModuleLayouts, Error> {
+    layouts::module_layouts(content, resolver)
+}
 
-        fn allow_duplicate_modules_and_convert_cfg(&mut self) -> bool {
-            self.allow_duplicate_modules_and_convert_cfgs
-        }
-    }
+/// Filesystem-based counterpart of [`module_layouts`]. Each file is read only once.
+///
+/// See [`read_full_crate_source_code`] documentation for security considerations.
+pub fn read_module_layouts(path: impl AsRef<std::path::Path>) -> Result<ModuleLayouts, Error> {
+    read_module_layouts_tracked(path, &mut LoadedFiles::default())
+}
 
-    expand_modules_into_inline_modules(
-        &mut root_source,
-        &mut MyResolver {
-            cfg_attr_path_handler,
-            parent_dir,
-            allow_duplicate_modules_and_convert_cfgs,
-        },
-    )?;
-    Ok(root_source)
+/// The same as [`read_module_layouts`], but also records every file path the loader tried to open into `files`,
+/// like [`read_full_crate_source_code_tracked`] does. Each path is recorded once.
+pub fn read_module_layouts_tracked(
+    path: impl AsRef<std::path::Path>,
+    files: &mut LoadedFiles,
+) -> Result<ModuleLayouts, Error> {
+    let path = path.as_ref();
+    let mut loaded = LoadedFiles::default();
+    let mut expander = Expander::new().filesystem(layouts::CachingFileSystem::default());
+    let mut resolver = expander.resolver(path, &mut loaded, None);
+    let ret = resolver
+        .read_root()
+        .and_then(|root_source| layouts::module_layouts(&root_source, &mut resolver));
+    files.read.extend(loaded.read.into_iter().unique());
+    files.missing.extend(loaded.missing.into_iter().unique());
+    ret
+}
+
+/// Load crate source code like [`read_full_crate_source_code_ex`] does, but return
+/// machine-readable [`ExpansionReport`] about modules and files instead of the code.
+///
+/// Errors do not cause this function to fail: they are recorded in the report instead.
+///
+/// See [`read_full_crate_source_code`] documentation for security considerations.
+pub fn read_expansion_report(
+    path: impl AsRef<std::path::Path>,
+    cfg_attr_path_handler: impl FnMut(syn::Meta) -> Result<bool, UserError>,
+    allow_duplicate_modules_and_convert_cfgs: bool,
+) -> ExpansionReport {
+    let path = path.as_ref();
+    let mut files = LoadedFiles::default();
+    let mut tree = Vec::new();
+    let mut diagnostics = expand_impl::Diagnostics::default();
+    let mut expander = Expander::new()
+        .cfg_provider(cfg_attr_path_handler)
+        .multimodule(allow_duplicate_modules_and_convert_cfgs);
+    let mut resolver = expander.resolver(path, &mut files, None);
+    let ret = resolver
+        .read_root()
+        .and_then(|mut root_source| expand_impl_root(&mut root_source.items, &mut resolver, &mut tree, &mut diagnostics));
+    ExpansionReport::new(path, &tree, &diagnostics, &files, ret.err().as_ref())
+}
+
+/// Combine already expanded binary crate and its library crate into a single file, e.g. for submitting
+/// to online judges or sharing snippets. `lib_name` is the name the binary refers to the library by
+/// (dashes are replaced by underscores).
+///
+/// * The library is put first into the binary as `mod lib_name { ... }`, with `dead_code`, `unused_imports` and `unused_macros` allowed.
+/// * `crate::` paths in the library (including `$crate::` and `crate::` in macros) are rewritten to `crate::lib_name::`.
+/// * `#[macro_export]` macros of the library, which end up in the root of the bundle, are re-exported from the module,
+///   so `lib_name::m!()` and `$crate::lib_name::m!()` keep working.
+/// * `lib_name::` paths in the binary (including in macros) are rewritten to `crate::lib_name::`.
+///   `extern crate lib_name;` is removed, `extern crate lib_name as other;` becomes `use crate::lib_name as other;`.
+///   `#[macro_use]` of it moves to the module.
+/// * `#![feature]`s of the library are moved to the binary, other crate-level attributes like `#![no_std]` are dropped.
+///
+/// Paths are rewritten syntactically, so local items shadowing the library name are not taken into account.
+///
+/// Example:
+///
+/// ```
+/// let bin: syn::File = syn::parse_quote! {
+///     use mylib::add;
+///     fn main() { println!("{}", add(1, mylib::two!())); }
+/// };
+/// let lib: syn::File = syn::parse_quote! {
+///     pub fn add(a: u32, b: u32) -> u32 { crate::util::id(a + b) }
+///     #[macro_export]
+///     macro_rules! two { () => { $crate::util::id(2) } }
+///     mod util { pub fn id(x: u32) -> u32 { x } }
+/// };
+/// let bundle = syn_file_expand::bundle(bin, lib, "mylib");
+///
+/// let expected: syn::File = syn::parse_quote! {
+///     #[allow(dead_code, unused_imports, unused_macros)]
+///     mod mylib {
+///         pub use crate::{two};
+///         pub fn add(a: u32, b: u32) -> u32 { crate::mylib::util::id(a + b) }
+///         #[macro_export]
+///         macro_rules! two { () => { $crate::mylib::util::id(2) } }
+///         mod util { pub fn id(x: u32) -> u32 { x } }
+///     }
+///     use crate::mylib::add;
+///     fn main() { println!("{}", add(1, crate::mylib::two!())); }
+/// };
+/// assert_eq!(quote::ToTokens::to_token_stream(&bundle).to_string(), quote::ToTokens::to_token_stream(&expected).to_string());
+/// ```
+pub fn bundle(bin: syn::File, lib: syn::File, lib_name: &str) -> syn::File {
+    bundle::bundle(bin, lib, lib_name)
+}
+
+/// Filesystem-based counterpart of [`bundle`]: load a binary crate from `bin_path` (e.g. `src/main.rs`)
+/// and its library crate from `lib_path` (e.g. `src/lib.rs`) like [`read_full_crate_source_code_ex`] does
+/// and combine them into a single file.
+///
+/// See [`read_full_crate_source_code`] documentation for security considerations.
+pub fn read_bundle(
+    bin_path: impl AsRef<std::path::Path>,
+    lib_path: impl AsRef<std::path::Path>,
+    lib_name: &str,
+    mut cfg_attr_path_handler: impl FnMut(syn::Meta) -> Result<bool, UserError>,
+    allow_duplicate_modules_and_convert_cfgs: bool,
+) -> Result<syn::File, Error> {
+    let bin = read_full_crate_source_code_ex(bin_path, &mut cfg_attr_path_handler, allow_duplicate_modules_and_convert_cfgs)?;
+    let lib = read_full_crate_source_code_ex(lib_path, &mut cfg_attr_path_handler, allow_duplicate_modules_and_convert_cfgs)?;
+    Ok(bundle(bin, lib, lib_name))
 }
 
 /// Load whole source code of a crate based on root `lib.rs` or `main.rs`.
@@ -342,4 +1105,22 @@ fn read_crate_impl(
 
 
 mod attrs;
+mod bundle;
+mod cfg_list;
+mod cfg_pass;
+mod delegate;
 mod expand_impl;
+mod expander;
+mod layouts;
+mod macro_rules;
+mod report;
+#[cfg(feature = "span-locations")]
+mod splice;
+
+pub use expander::{Expander, Expansion, FileSystem, StdFileSystem, UnparsedFile};
+#[cfg(feature = "span-locations")]
+pub use expander::TextExpansion;
+pub use layouts::{InvalidConfiguration, ModuleLayout, ModuleLayouts};
+pub use report::{
+    ExpansionReport, ModuleEntry, ReportedError, SkippedModule, UnresolvedModuleEntry,
+};
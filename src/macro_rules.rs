@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+
+use proc_macro2::{Delimiter, Group, Ident, TokenStream, TokenTree};
+
+use crate::UnexpandedMacroReason;
+
+/// Locally defined `macro_rules!` macro, see [`Resolver::evaluate_macro_rules`](crate::Resolver::evaluate_macro_rules)
+#[derive(Clone)]
+pub(crate) struct MacroRules {
+    pub name: Ident,
+    /// `None` if the definition uses something that is not supported
+    rules: Option<Vec<Rule>>,
+}
+
+#[derive(Clone)]
+struct Rule {
+    matcher: Vec<Matcher>,
+    transcriber: Vec<Transcriber>,
+}
+
+#[derive(Clone, Copy)]
+enum Fragment {
+    Ident,
+    Path,
+    Tt,
+    Literal,
+    Vis,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RepeatOp {
+    ZeroOrMore,
+    OneOrMore,
+    ZeroOrOne,
+}
+
+#[derive(Clone)]
+enum Matcher {
+    Token(TokenTree),
+    Group(Delimiter, Vec<Matcher>),
+    Fragment(String, Fragment),
+    Repeat(Vec<Matcher>, Option<TokenTree>, RepeatOp),
+}
+
+#[derive(Clone)]
+enum Transcriber {
+    Token(TokenTree),
+    Group(Group, Vec<Transcriber>),
+    Var(Ident),
+    /// `$crate`, which is just `crate` for a macro defined in the same crate
+    Crate(Ident),
+    Repeat(Vec<Transcriber>, Option<TokenTree>),
+}
+
+/// Tokens captured by a fragment, or per-iteration captures for fragments inside repetitions
+#[derive(Clone)]
+enum Binding {
+    Leaf(Vec<TokenTree>),
+    Seq(Vec<Binding>),
+}
+
+type Bindings = HashMap<String, Binding>;
+
+impl MacroRules {
+    /// Returns `None` if it is not a `macro_rules!` definition
+    pub fn parse(item_macro: &syn::ItemMacro) -> Option<MacroRules> {
+        if !item_macro.mac.path.is_ident("macro_rules") {
+            return None;
+        }
+        Some(MacroRules {
+            name: item_macro.ident.clone()?,
+            rules: parse_rules(item_macro.mac.tokens.clone()),
+        })
+    }
+
+    /// Expand invocation using the first matching rule
+    pub fn expand(&self, mac: &syn::Macro) -> Result<Vec<syn::Item>, UnexpandedMacroReason> {
+        let rules = self
+            .rules
+            .as_ref()
+            .ok_or(UnexpandedMacroReason::UnsupportedDefinition)?;
+        let tokens: Vec<TokenTree> = mac.tokens.clone().into_iter().collect();
+        for rule in rules {
+            let mut bindings = Bindings::new();
+            if match_prefix(&rule.matcher, &tokens, &mut bindings) != Some(tokens.len()) {
+                continue;
+            }
+            let mut out = TokenStream::new();
+            transcribe(&rule.transcriber, &bindings, &mut out)
+                .ok_or(UnexpandedMacroReason::InvalidExpansion)?;
+            let file: syn::File =
+                syn::parse2(out).map_err(|_| UnexpandedMacroReason::InvalidExpansion)?;
+            return Ok(file.items);
+        }
+        Err(UnexpandedMacroReason::NoMatchingRule)
+    }
+}
+
+fn is_punct(tt: &TokenTree, c: char) -> bool {
+    matches!(tt, TokenTree::Punct(p) if p.as_char() == c)
+}
+
+fn token_eq(a: &TokenTree, b: &TokenTree) -> bool {
+    match (a, b) {
+        (TokenTree::Punct(a), TokenTree::Punct(b)) => a.as_char() == b.as_char(),
+        (TokenTree::Ident(a), TokenTree::Ident(b)) => a == b,
+        (TokenTree::Literal(a), TokenTree::Literal(b)) => a.to_string() == b.to_string(),
+        _ => false,
+    }
+}
+
+/// `(matcher) => {transcriber};` rules
+fn parse_rules(tokens: TokenStream) -> Option<Vec<Rule>> {
+    let tts: Vec<TokenTree> = tokens.into_iter().collect();
+    let mut rules = Vec::new();
+    let mut i = 0;
+    while i < tts.len() {
+        let TokenTree::Group(matcher) = &tts[i] else { return None };
+        if !is_punct(tts.get(i + 1)?, '=') || !is_punct(tts.get(i + 2)?, '>') {
+            return None;
+        }
+        let TokenTree::Group(transcriber) = tts.get(i + 3)? else { return None };
+        rules.push(Rule {
+            matcher: parse_matcher(matcher.stream())?,
+            transcriber: parse_transcriber(transcriber.stream())?,
+        });
+        i += 4;
+        if let Some(tt) = tts.get(i) {
+            if !is_punct(tt, ';') {
+                return None;
+            }
+            i += 1;
+        }
+    }
+    Some(rules)
+}
+
+/// Separator and operator after `$(...)`, with number of tokens they take
+fn parse_repeat_op(tts: &[TokenTree]) -> Option<(Option<TokenTree>, RepeatOp, usize)> {
+    let op = |tt: &TokenTree| match tt {
+        TokenTree::Punct(p) if p.as_char() == '*' => Some(RepeatOp::ZeroOrMore),
+        TokenTree::Punct(p) if p.as_char() == '+' => Some(RepeatOp::OneOrMore),
+        TokenTree::Punct(p) if p.as_char() == '?' => Some(RepeatOp::ZeroOrOne),
+        _ => None,
+    };
+    let first = tts.first()?;
+    if let Some(op) = op(first) {
+        return Some((None, op, 1));
+    }
+    if matches!(first, TokenTree::Group(..)) {
+        return None;
+    }
+    Some((Some(first.clone()), op(tts.get(1)?)?, 2))
+}
+
+fn parse_matcher(tokens: TokenStream) -> Option<Vec<Matcher>> {
+    let tts: Vec<TokenTree> = tokens.into_iter().collect();
+    let mut ret = Vec::new();
+    let mut i = 0;
+    while i < tts.len() {
+        match &tts[i] {
+            TokenTree::Punct(p) if p.as_char() == '$' => match tts.get(i + 1)? {
+                TokenTree::Ident(name) => {
+                    if !is_punct(tts.get(i + 2)?, ':') {
+                        return None;
+                    }
+                    let TokenTree::Ident(kind) = tts.get(i + 3)? else { return None };
+                    let kind = match kind.to_string().as_str() {
+                        "ident" => Fragment::Ident,
+                        "path" => Fragment::Path,
+                        "tt" => Fragment::Tt,
+                        "literal" => Fragment::Literal,
+                        "vis" => Fragment::Vis,
+                        _ => return None,
+                    };
+                    ret.push(Matcher::Fragment(name.to_string(), kind));
+                    i += 4;
+                }
+                TokenTree::Group(g) if g.delimiter() == Delimiter::Parenthesis => {
+                    let inner = parse_matcher(g.stream())?;
+                    let (sep, op, len) = parse_repeat_op(&tts[i + 2..])?;
+                    ret.push(Matcher::Repeat(inner, sep, op));
+                    i += 2 + len;
+                }
+                _ => return None,
+            },
+            TokenTree::Group(g) => {
+                ret.push(Matcher::Group(g.delimiter(), parse_matcher(g.stream())?));
+                i += 1;
+            }
+            tt => {
+                ret.push(Matcher::Token(tt.clone()));
+                i += 1;
+            }
+        }
+    }
+    Some(ret)
+}
+
+fn parse_transcriber(tokens: TokenStream) -> Option<Vec<Transcriber>> {
+    let tts: Vec<TokenTree> = tokens.into_iter().collect();
+    let mut ret = Vec::new();
+    let mut i = 0;
+    while i < tts.len() {
+        match &tts[i] {
+            TokenTree::Punct(p) if p.as_char() == '$' => match tts.get(i + 1)? {
+                TokenTree::Ident(name) if name == "crate" => {
+                    ret.push(Transcriber::Crate(name.clone()));
+                    i += 2;
+                }
+                TokenTree::Ident(name) => {
+                    ret.push(Transcriber::Var(name.clone()));
+                    i += 2;
+                }
+                TokenTree::Group(g) if g.delimiter() == Delimiter::Parenthesis => {
+                    let inner = parse_transcriber(g.stream())?;
+                    let (sep, _, len) = parse_repeat_op(&tts[i + 2..])?;
+                    ret.push(Transcriber::Repeat(inner, sep));
+                    i += 2 + len;
+                }
+                _ => return None,
+            },
+            TokenTree::Group(g) => {
+                ret.push(Transcriber::Group(g.clone(), parse_transcriber(g.stream())?));
+                i += 1;
+            }
+            tt => {
+                ret.push(Transcriber::Token(tt.clone()));
+                i += 1;
+            }
+        }
+    }
+    Some(ret)
+}
+
+/// Number of tokens the fragment takes from the beginning of `tts`
+fn match_fragment(kind: Fragment, tts: &[TokenTree]) -> Option<usize> {
+    match kind {
+        Fragment::Tt => tts.first().map(|_| 1),
+        Fragment::Ident => match tts.first()? {
+            TokenTree::Ident(x) if x != "_" => Some(1),
+            _ => None,
+        },
+        Fragment::Literal => match tts {
+            [TokenTree::Literal(_), ..] => Some(1),
+            [minus, TokenTree::Literal(_), ..] if is_punct(minus, '-') => Some(2),
+            _ => None,
+        },
+        Fragment::Path => {
+            let is_colons = |i: usize| {
+                tts.get(i).is_some_and(|x| is_punct(x, ':'))
+                    && tts.get(i + 1).is_some_and(|x| is_punct(x, ':'))
+            };
+            let mut i = if is_colons(0) { 2 } else { 0 };
+            loop {
+                if !matches!(tts.get(i)?, TokenTree::Ident(..)) {
+                    return None;
+                }
+                i += 1;
+                if !is_colons(i) {
+                    return Some(i);
+                }
+                i += 2;
+            }
+        }
+        Fragment::Vis => match tts {
+            [TokenTree::Ident(x), TokenTree::Group(g), ..]
+                if x == "pub" && g.delimiter() == Delimiter::Parenthesis =>
+            {
+                Some(2)
+            }
+            [TokenTree::Ident(x), ..] if x == "pub" => Some(1),
+            _ => Some(0),
+        },
+    }
+}
+
+/// Names of fragments in the matcher, including nested repetitions
+fn matcher_vars(matchers: &[Matcher], out: &mut Vec<String>) {
+    for m in matchers {
+        match m {
+            Matcher::Token(_) => (),
+            Matcher::Group(_, inner) | Matcher::Repeat(inner, _, _) => matcher_vars(inner, out),
+            Matcher::Fragment(name, _) => out.push(name.clone()),
+        }
+    }
+}
+
+/// Match a prefix of `tts` greedily, without backtracking. Returns number of tokens consumed.
+fn match_prefix(matchers: &[Matcher], tts: &[TokenTree], bindings: &mut Bindings) -> Option<usize> {
+    let mut pos = 0;
+    for m in matchers {
+        match m {
+            Matcher::Token(expected) => {
+                if !token_eq(expected, tts.get(pos)?) {
+                    return None;
+                }
+                pos += 1;
+            }
+            Matcher::Group(delimiter, inner) => {
+                let TokenTree::Group(g) = tts.get(pos)? else { return None };
+                if g.delimiter() != *delimiter {
+                    return None;
+                }
+                let inner_tts: Vec<TokenTree> = g.stream().into_iter().collect();
+                if match_prefix(inner, &inner_tts, bindings)? != inner_tts.len() {
+                    return None;
+                }
+                pos += 1;
+            }
+            Matcher::Fragment(name, kind) => {
+                let len = match_fragment(*kind, &tts[pos..])?;
+                bindings.insert(name.clone(), Binding::Leaf(tts[pos..pos + len].to_vec()));
+                pos += len;
+            }
+            Matcher::Repeat(inner, separator, op) => {
+                let mut iterations: Vec<Bindings> = Vec::new();
+                while !(*op == RepeatOp::ZeroOrOne && iterations.len() == 1) {
+                    let mut p = pos;
+                    if let (Some(separator), false) = (separator, iterations.is_empty()) {
+                        match tts.get(p) {
+                            Some(x) if token_eq(separator, x) => p += 1,
+                            _ => break,
+                        }
+                    }
+                    let mut iteration = Bindings::new();
+                    let Some(len) = match_prefix(inner, &tts[p..], &mut iteration) else { break };
+                    if p + len == pos {
+                        break;
+                    }
+                    iterations.push(iteration);
+                    pos = p + len;
+                }
+                if *op == RepeatOp::OneOrMore && iterations.is_empty() {
+                    return None;
+                }
+                let mut names = Vec::new();
+                matcher_vars(inner, &mut names);
+                for name in names {
+                    let seq = iterations
+                        .iter()
+                        .map(|x| x.get(&name).cloned().unwrap_or(Binding::Seq(Vec::new())))
+                        .collect();
+                    bindings.insert(name, Binding::Seq(seq));
+                }
+            }
+        }
+    }
+    Some(pos)
+}
+
+/// Names of variables used in the transcriber, including nested repetitions
+fn transcriber_vars(transcribers: &[Transcriber], out: &mut Vec<String>) {
+    for t in transcribers {
+        match t {
+            Transcriber::Token(_) | Transcriber::Crate(_) => (),
+            Transcriber::Group(_, inner) | Transcriber::Repeat(inner, _) => transcriber_vars(inner, out),
+            Transcriber::Var(name) => out.push(name.to_string()),
+        }
+    }
+}
+
+fn transcribe(transcribers: &[Transcriber], bindings: &Bindings, out: &mut TokenStream) -> Option<()> {
+    for t in transcribers {
+        match t {
+            Transcriber::Token(tt) => out.extend([tt.clone()]),
+            Transcriber::Group(g, inner) => {
+                let mut stream = TokenStream::new();
+                transcribe(inner, bindings, &mut stream)?;
+                let mut group = Group::new(g.delimiter(), stream);
+                group.set_span(g.span());
+                out.extend([TokenTree::Group(group)]);
+            }
+            Transcriber::Var(name) => match bindings.get(&name.to_string())? {
+                Binding::Leaf(tts) => out.extend(tts.iter().cloned()),
+                Binding::Seq(_) => return None,
+            },
+            Transcriber::Crate(x) => out.extend([TokenTree::Ident(Ident::new("crate", x.span()))]),
+            Transcriber::Repeat(inner, separator) => {
+                let mut names = Vec::new();
+                transcriber_vars(inner, &mut names);
+                let mut len = None;
+                for name in &names {
+                    if let Some(Binding::Seq(seq)) = bindings.get(name) {
+                        if len.is_some_and(|x| x != seq.len()) {
+                            return None;
+                        }
+                        len = Some(seq.len());
+                    }
+                }
+                for i in 0..len? {
+                    if let (Some(separator), true) = (separator, i > 0) {
+                        out.extend([separator.clone()]);
+                    }
+                    let mut iteration = bindings.clone();
+                    for name in &names {
+                        if let Some(Binding::Seq(seq)) = bindings.get(name) {
+                            iteration.insert(name.clone(), seq[i].clone());
+                        }
+                    }
+                    transcribe(inner, &iteration, out)?;
+                }
+            }
+        }
+    }
+    Some(())
+}
@@ -0,0 +1,162 @@
+use std::path::Path;
+
+use quote::ToTokens;
+
+use crate::{expand_impl::Diagnostics, Error, LoadedFiles, ModuleTree, PathForDisplay, UnresolvedReason};
+
+/// Machine-readable summary of loading a crate, returned by [`read_expansion_report`](crate::read_expansion_report).
+///
+/// With `serde` feature enabled, it implements `serde::Serialize`.
+/// Syntax elements (paths, `cfg` predicates, visibility) are represented as strings.
+/// Fields may be added in future versions, but existing fields are not changed
+/// without incrementing [`ExpansionReport::SCHEMA_VERSION`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ExpansionReport {
+    /// Always [`ExpansionReport::SCHEMA_VERSION`]
+    pub schema_version: u32,
+    /// Root file the crate was loaded from
+    pub root_file: String,
+    /// Modules of the expanded code, parents before children, except for those in `skipped_modules`
+    /// and unresolved modules that were dropped in full crate tree mode
+    pub modules: Vec<ModuleEntry>,
+    /// Inline and file modules that were left out because their `#[cfg]` was false.
+    /// `cfg`s of inline modules are only evaluated if there are modules to load inside.
+    pub skipped_modules: Vec<SkippedModule>,
+    /// Modules that were left unexpanded because no file was loaded for them
+    pub unresolved_modules: Vec<UnresolvedModuleEntry>,
+    /// Files that were successfully read
+    pub files_read: Vec<String>,
+    /// Candidate files that were probed, but could not be opened
+    pub files_missing: Vec<String>,
+    /// Errors that stopped the expansion. The report may be incomplete if this is not empty.
+    pub errors: Vec<ReportedError>,
+}
+
+/// Module as listed in [`ExpansionReport`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ModuleEntry {
+    /// Full path of the module, e.g. `net::tcp`
+    pub path: String,
+    /// File the module was loaded from, relative to the root file's directory.
+    /// `None` for inline modules and for modules that were left unexpanded.
+    pub file: Option<String>,
+    /// Visibility of the module, e.g. `pub(crate)`. Empty string for private modules.
+    pub visibility: String,
+    /// `cfg` predicates that gate this module, see [`ModuleTree::cfg`]
+    pub cfg: Vec<String>,
+    /// Whether other modules with the same path are present (for different `cfg`s) in full crate tree mode
+    pub duplicate: bool,
+}
+
+/// Module that was skipped due to its `#[cfg]`, as listed in [`ExpansionReport`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SkippedModule {
+    /// Full path of the module
+    pub path: String,
+    /// `cfg` predicate that was considered false
+    pub cfg: String,
+}
+
+/// Unresolved module as listed in [`ExpansionReport`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct UnresolvedModuleEntry {
+    /// Full path of the module
+    pub path: String,
+    /// Files that were tried, relative to the root file's directory
+    pub candidates: Vec<String>,
+    /// `declined` if resolver returned no code, `not_found` if no candidate file exists
+    pub reason: String,
+}
+
+/// Error as listed in [`ExpansionReport`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ReportedError {
+    /// Full path of the module being expanded. Empty for the root module.
+    pub module: String,
+    /// Human-readable error message
+    pub message: String,
+}
+
+fn tokens_to_string(x: &impl ToTokens) -> String {
+    x.to_token_stream().to_string()
+}
+
+fn flatten(prefix: &str, tree: &[ModuleTree], out: &mut Vec<ModuleEntry>) {
+    for node in tree {
+        let path = if prefix.is_empty() {
+            node.name.to_string()
+        } else {
+            format!("{}::{}", prefix, node.name)
+        };
+        let duplicate = tree.iter().filter(|x| x.name == node.name).count() > 1;
+        out.push(ModuleEntry {
+            path: path.clone(),
+            file: node.file.as_ref().map(|x| x.to_string_lossy().into_owned()),
+            visibility: tokens_to_string(&node.vis),
+            cfg: node.cfg.iter().map(tokens_to_string).collect(),
+            duplicate,
+        });
+        flatten(&path, &node.children, out);
+    }
+}
+
+impl ExpansionReport {
+    /// Current version of the report format
+    pub const SCHEMA_VERSION: u32 = 1;
+
+    pub(crate) fn new(
+        root_file: &Path,
+        tree: &[ModuleTree],
+        diagnostics: &Diagnostics,
+        files: &LoadedFiles,
+        error: Option<&Error>,
+    ) -> ExpansionReport {
+        let mut modules = Vec::new();
+        flatten("", tree, &mut modules);
+        let paths_to_strings = |x: &[std::path::PathBuf]| {
+            x.iter()
+                .map(|x| x.to_string_lossy().into_owned())
+                .collect()
+        };
+        ExpansionReport {
+            schema_version: Self::SCHEMA_VERSION,
+            root_file: root_file.to_string_lossy().into_owned(),
+            modules,
+            skipped_modules: diagnostics
+                .skipped
+                .iter()
+                .map(|(path, cfg)| SkippedModule {
+                    path: PathForDisplay(path).to_string(),
+                    cfg: tokens_to_string(cfg),
+                })
+                .collect(),
+            unresolved_modules: diagnostics
+                .unresolved
+                .iter()
+                .map(|x| UnresolvedModuleEntry {
+                    path: PathForDisplay(&x.module).to_string(),
+                    candidates: paths_to_strings(&x.candidates),
+                    reason: match x.reason {
+                        UnresolvedReason::Declined => "declined",
+                        UnresolvedReason::NotFound => "not_found",
+                    }
+                    .to_owned(),
+                })
+                .collect(),
+            files_read: paths_to_strings(&files.read),
+            files_missing: paths_to_strings(&files.missing),
+            errors: error
+                .map(|e| ReportedError {
+                    module: PathForDisplay(&e.module).to_string(),
+                    message: e.inner.to_string(),
+                })
+                .into_iter()
+                .collect(),
+        }
+    }
+}
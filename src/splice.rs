@@ -0,0 +1,138 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use proc_macro2::{LineColumn, TokenStream, TokenTree};
+
+use crate::expand_impl::ExpandedDeclaration;
+
+/// Added to the indentation of the `mod name;` line for the spliced file content
+const INDENT: &str = "    ";
+
+/// Text of the expanded crate: `texts` of loaded files (keyed the same way as [`FileSystem`](crate::FileSystem) paths)
+/// with each expanded `mod name;` declaration replaced by `mod name { ... }` containing the re-indented file text
+pub(crate) fn splice_files(
+    texts: &HashMap<PathBuf, String>,
+    root: &Path,
+    declarations: &[ExpandedDeclaration],
+) -> String {
+    Splicer {
+        texts,
+        root,
+        declarations,
+    }
+    .render(None)
+}
+
+struct Splicer<'a> {
+    texts: &'a HashMap<PathBuf, String>,
+    root: &'a Path,
+    declarations: &'a [ExpandedDeclaration],
+}
+
+impl<'a> Splicer<'a> {
+    fn text(&self, file: Option<&Path>) -> &'a str {
+        let path = match (file, self.root.parent()) {
+            (None, _) => self.root.to_owned(),
+            (Some(file), Some(parent_dir)) => parent_dir.join(file),
+            (Some(file), None) => file.to_owned(),
+        };
+        let text = self.texts.get(&path).map_or("", String::as_str);
+        // `syn` skips it, so it would shift columns of the first line
+        text.strip_prefix('\u{feff}').unwrap_or(text)
+    }
+
+    fn render(&self, file: Option<&Path>) -> String {
+        let mut text = self.text(file).to_owned();
+        let mut edits: Vec<(Range<usize>, String)> = Vec::new();
+        for declaration in self.declarations.iter().filter(|x| x.parent.as_deref() == file) {
+            let Some(semicolon) = offset(&text, declaration.semicolon) else { continue };
+            if !text[semicolon..].starts_with(';') {
+                continue;
+            }
+            let line_start = text[..semicolon].rfind('\n').map_or(0, |x| x + 1);
+            let line = &text[line_start..];
+            let indent = &line[..line.len() - line.trim_start().len()];
+            let content = self.render(Some(&declaration.file));
+            let content = content.trim_end();
+            let replacement = if content.trim_start().is_empty() {
+                " {}".to_owned()
+            } else {
+                format!(" {{\n{}\n{indent}}}", reindent(content, &format!("{indent}{INDENT}")))
+            };
+            edits.push((semicolon..semicolon + 1, replacement));
+            for &(start, end) in &declaration.path_attrs {
+                let (Some(start), Some(end)) = (offset(&text, start), offset(&text, end)) else { continue };
+                edits.push((whole_line_if_alone(&text, start..end), String::new()));
+            }
+        }
+        edits.sort_by_key(|(range, _)| std::cmp::Reverse(range.start));
+        for (range, replacement) in edits {
+            text.replace_range(range, &replacement);
+        }
+        text
+    }
+}
+
+/// Byte offset of the position in the text, `None` if it is out of range
+fn offset(text: &str, position: LineColumn) -> Option<usize> {
+    let line_start = if position.line == 1 {
+        0
+    } else {
+        text.match_indices('\n').nth(position.line.checked_sub(2)?)?.0 + 1
+    };
+    let line = &text[line_start..];
+    let line = &line[..line.find('\n').unwrap_or(line.len())];
+    let column = match line.char_indices().nth(position.column) {
+        Some((x, _)) => x,
+        None if line.chars().count() == position.column => line.len(),
+        None => return None,
+    };
+    Some(line_start + column)
+}
+
+/// Extend the range to its whole line (including the line feed) if there is only whitespace around it
+fn whole_line_if_alone(text: &str, range: Range<usize>) -> Range<usize> {
+    let line_start = text[..range.start].rfind('\n').map_or(0, |x| x + 1);
+    let line_end = text[range.end..].find('\n').map_or(text.len(), |x| range.end + x + 1);
+    if text[line_start..range.start].trim().is_empty() && text[range.end..line_end].trim().is_empty() {
+        line_start..line_end
+    } else {
+        range
+    }
+}
+
+/// Prepend `indent` to each non-blank line, except for continuation lines of multi-line literals
+fn reindent(text: &str, indent: &str) -> String {
+    let mut literal_lines = HashSet::new();
+    if let Ok(tokens) = text.parse::<TokenStream>() {
+        collect_literal_lines(tokens, &mut literal_lines);
+    }
+    let mut ret = String::with_capacity(text.len());
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            ret.push('\n');
+        }
+        if !line.trim().is_empty() && !literal_lines.contains(&(i + 1)) {
+            ret.push_str(indent);
+        }
+        ret.push_str(line);
+    }
+    ret
+}
+
+/// Record 1-based numbers of lines that start inside a literal (including doc comments)
+fn collect_literal_lines(tokens: TokenStream, lines: &mut HashSet<usize>) {
+    for token in tokens {
+        match token {
+            TokenTree::Group(group) => collect_literal_lines(group.stream(), lines),
+            TokenTree::Literal(literal) => {
+                let span = literal.span();
+                lines.extend(span.start().line + 1..=span.end().line);
+            }
+            TokenTree::Ident(_) | TokenTree::Punct(_) => (),
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use quote::quote as q;
+use quote::{quote as q, ToTokens};
 //use pretty_assertions::assert_eq;
 
 #[test]
@@ -69,3 +69,360 @@ fn fullsource_withdup() {
 
     assert_eq!(prettyplease::unparse(&src), prettyplease::unparse(&expected));
 }
+
+#[test]
+fn fullsource_tracked() {
+    let mut dir = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    dir.push("resources"); 
+    dir.push("sample"); 
+
+    let mut files = syn_file_expand::LoadedFiles::default();
+    syn_file_expand::read_full_crate_source_code_tracked(dir.join("lib.rs"), |_|Ok(false), false, &mut files).unwrap();
+
+    assert_eq!(files.read.len(), 13);
+    assert_eq!(files.read[0], dir.join("lib.rs"));
+    assert!(files.read.contains(&dir.join("plain.rs")));
+    assert!(files.read.contains(&dir.join("with_mod/mod.rs")));
+    assert!(files.read.contains(&dir.join("with_mod_path/with_mod_path_inner.rs")));
+    assert!(files.missing.contains(&dir.join("plain/mod.rs")));
+    assert!(files.missing.contains(&dir.join("with_mod.rs")));
+    assert!(!files.missing.contains(&dir.join("plain.rs")));
+}
+
+#[test]
+fn fullsource_module_tree() {
+    let mut sample = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    sample.push("resources"); 
+    sample.push("withdup"); 
+    sample.push("lib.rs"); 
+
+    let tree = syn_file_expand::read_module_tree(sample, |_|Ok(true), true).unwrap();
+
+    let summary : Vec<_> = tree.iter().map(|x| (
+        x.name.to_string(),
+        x.file.clone().unwrap(),
+        x.cfg.iter().map(|c|c.to_token_stream().to_string()).collect::<Vec<_>>(),
+    )).collect();
+    assert_eq!(summary, vec![
+        ("duplicate_plain".to_owned(), "duplicate_plain/mod.rs".into(), vec![]),
+        ("with_path".to_owned(), "a.rs".into(), vec![]),
+        ("tricky".to_owned(), "b.rs".into(), vec![q!(feature="b").to_string()]),
+        ("tricky".to_owned(), "c.rs".into(), vec![q!(feature="c").to_string()]),
+        ("tricky".to_owned(), "tricky.rs".into(), vec![q!(not(any(feature="b", feature="c"))).to_string()]),
+    ]);
+    assert!(tree.iter().all(|x|x.children.is_empty()));
+}
+
+#[test]
+fn fullsource_report() {
+    let mut sample = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    sample.push("resources"); 
+    sample.push("withdup"); 
+    sample.push("lib.rs"); 
+
+    let report = syn_file_expand::read_expansion_report(&sample, |cfg|Ok(cfg.path().is_ident("feature")), false);
+
+    assert_eq!(report.schema_version, syn_file_expand::ExpansionReport::SCHEMA_VERSION);
+    assert_eq!(report.modules.len(), 2);
+    assert_eq!(report.modules[1].path, "with_path");
+    assert_eq!(report.modules[1].file.as_deref(), Some("a.rs"));
+    assert!(report.modules.iter().all(|x|!x.duplicate));
+    assert!(report.skipped_modules.is_empty());
+    assert_eq!(report.errors.len(), 1);
+    assert_eq!(report.errors[0].module, "tricky");
+}
+
+#[test]
+fn fullsource_report_disabled_modules() {
+    let mut sample = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    sample.push("resources");
+    sample.push("report");
+    sample.push("lib.rs");
+
+    let report = syn_file_expand::read_expansion_report(&sample, |cfg| Ok(cfg == syn::parse_quote!(feature = "on")), false);
+
+    assert!(report.errors.is_empty());
+    let modules: Vec<_> = report.modules.iter().map(|x| x.path.as_str()).collect();
+    assert_eq!(modules, ["enabled_inline", "enabled_inline::nested", "plain_inline"]);
+    let skipped: Vec<_> = report.skipped_modules.iter().map(|x| x.path.as_str()).collect();
+    assert_eq!(skipped, ["disabled_file", "disabled_inline"]);
+    assert!(report.skipped_modules.iter().all(|x| x.cfg == q!(feature = "off").to_string()));
+}
+
+#[test]
+fn fullsource_cfg_predicates() {
+    let mut sample = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    sample.push("resources");
+    sample.push("withdup");
+    sample.push("lib.rs");
+
+    let cfgs = syn_file_expand::read_cfg_predicates(sample).unwrap();
+
+    let cfgs: Vec<_> = cfgs
+        .iter()
+        .map(|x| (x.predicate.as_str(), x.locations.len(), x.locations[0].line))
+        .collect();
+    let lines = if cfg!(feature = "span-locations") { (6, 7) } else { (0, 0) };
+    assert_eq!(cfgs, vec![("feature = \"b\"", 1, lines.0), ("feature = \"c\"", 1, lines.1)]);
+}
+
+#[test]
+fn fullsource_cfg_predicates_tracked() {
+    let mut dir = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    dir.push("resources");
+    dir.push("withdup");
+
+    let mut files = syn_file_expand::LoadedFiles::default();
+    syn_file_expand::read_cfg_predicates_tracked(dir.join("lib.rs"), &mut files).unwrap();
+
+    assert_eq!(files.read[0], dir.join("lib.rs"));
+    assert!(files.read.contains(&dir.join("b.rs")));
+    assert!(files.read.contains(&dir.join("c.rs")));
+    assert!(files.read.contains(&dir.join("tricky.rs")));
+}
+
+#[test]
+fn fullsource_module_layouts() {
+    let mut sample = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    sample.push("resources");
+    sample.push("withdup");
+    sample.push("lib.rs");
+
+    let layouts = syn_file_expand::read_module_layouts(sample).unwrap();
+
+    assert_eq!(layouts.atoms, ["feature = \"b\"", "feature = \"c\""]);
+    let tricky_files: Vec<_> = layouts
+        .layouts
+        .iter()
+        .map(|x| (x.enabled.len(), x.modules[2].file.clone().unwrap()))
+        .collect();
+    assert_eq!(
+        tricky_files,
+        vec![(0, "tricky.rs".into()), (1, "b.rs".into()), (1, "c.rs".into())]
+    );
+    assert_eq!(layouts.invalid.len(), 1);
+    assert_eq!(layouts.invalid[0].enabled.len(), 2);
+}
+
+#[test]
+fn fullsource_module_layouts_tracked() {
+    let mut dir = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    dir.push("resources");
+    dir.push("withdup");
+
+    let mut files = syn_file_expand::LoadedFiles::default();
+    syn_file_expand::read_module_layouts_tracked(dir.join("lib.rs"), &mut files).unwrap();
+
+    assert_eq!(files.read[0], dir.join("lib.rs"));
+    assert!(files.read.contains(&dir.join("b.rs")));
+    assert!(files.read.contains(&dir.join("c.rs")));
+    assert!(files.read.contains(&dir.join("tricky.rs")));
+    assert_eq!(files.read.iter().filter(|x| **x == dir.join("tricky.rs")).count(), 1);
+}
+
+#[test]
+fn fullsource_withdup_first_path_wins() {
+    let mut sample = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    sample.push("resources");
+    sample.push("withdup");
+    sample.push("lib.rs");
+
+    let src = syn_file_expand::Expander::new()
+        .cfg_provider(|_| Ok(true))
+        .multimodule(true)
+        .first_path_wins(true)
+        .expand_path(&sample)
+        .unwrap()
+        .file;
+
+    let expected: syn::File = syn::parse2(q! {
+        mod duplicate_plain {
+            struct DuplicatePlainMod;
+        }
+
+        mod with_path {
+            struct A;
+        }
+
+        #[cfg(feature="b")]
+        mod tricky {
+            struct B;
+        }
+
+        #[cfg(all(feature="c", not(feature="b")))]
+        mod tricky {
+            struct C;
+        }
+
+        #[cfg(not(any(feature="b", feature="c")))]
+        mod tricky {
+            struct Tricky;
+        }
+    })
+    .unwrap();
+
+    assert_eq!(prettyplease::unparse(&src), prettyplease::unparse(&expected));
+
+    // Without multimodule mode, the first enabled `#[cfg_attr(..., path)]` is used
+    let tree = syn_file_expand::Expander::new()
+        .cfg_provider(|_| Ok(true))
+        .first_path_wins(true)
+        .expand_path(&sample)
+        .unwrap()
+        .modules;
+    assert_eq!(tree[2].file, Some("b.rs".into()));
+}
+
+fn flatten_tree(prefix: &str, tree: &[syn_file_expand::ModuleTree], out: &mut Vec<(String, Option<std::path::PathBuf>)>) {
+    for node in tree {
+        let path = format!("{}{}", prefix, node.name);
+        out.push((path.clone(), node.file.clone()));
+        flatten_tree(&format!("{}::", path), &node.children, out);
+    }
+}
+
+/// Module file locations documented in
+/// <https://doc.rust-lang.org/reference/items/modules.html#the-path-attribute>
+#[test]
+fn fullsource_path_conformance() {
+    let mut sample = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    sample.push("resources");
+    sample.push("pathconf");
+    sample.push("lib.rs");
+
+    let tree = syn_file_expand::read_module_tree(sample, |_| Ok(false), false).unwrap();
+    let mut modules = vec![];
+    flatten_tree("", &tree, &mut modules);
+
+    let expected: Vec<(String, Option<std::path::PathBuf>)> = [
+        ("a", Some("a.rs")),
+        ("a::p", Some("a_sibling.rs")),
+        ("a::n", Some("a/n.rs")),
+        ("a::inline", None),
+        ("a::inline::inner", Some("a/inline/other.rs")),
+        ("a::inline::nat", Some("a/inline/nat.rs")),
+        ("a::inline_path", None),
+        ("a::inline_path::q", Some("a_renamed/q.rs")),
+        ("a::block", Some("a_block.rs")),
+        ("a::scoped", None),
+        ("a::scoped::x", Some("scoped/x.rs")),
+        ("b", Some("b/mod.rs")),
+        ("b::p", Some("b/b_sibling.rs")),
+        ("b::inline", None),
+        ("b::inline::inner", Some("b/inline/other.rs")),
+        ("e", Some("sub/e.rs")),
+        ("e::f", Some("sub/f.rs")),
+        ("inline", None),
+        ("inline::c", Some("inline/c.rs")),
+        ("inline::d", Some("inline/d.rs")),
+        ("inline_path", None),
+        ("inline_path::g", Some("renamed/g.rs")),
+        ("h", Some("sub/h.rs")),
+    ]
+    .into_iter()
+    .map(|(m, f)| (m.to_owned(), f.map(Into::into)))
+    .collect();
+    assert_eq!(modules, expected);
+}
+
+#[test]
+fn fullsource_raw_identifiers() {
+    let mut sample = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    sample.push("resources");
+    sample.push("rawident");
+    sample.push("lib.rs");
+
+    let tree = syn_file_expand::read_module_tree(sample, |_| Ok(false), false).unwrap();
+    let mut modules = vec![];
+    flatten_tree("", &tree, &mut modules);
+
+    let expected: Vec<(String, Option<std::path::PathBuf>)> = [
+        ("r#type", Some("type.rs")),
+        ("r#type::r#impl", Some("type/impl.rs")),
+        ("r#async", Some("async/mod.rs")),
+        ("r#async::r#dyn", Some("async/dyn.rs")),
+        ("r#match", None),
+        ("r#match::r#fn", Some("match/fn.rs")),
+    ]
+    .into_iter()
+    .map(|(m, f)| (m.to_owned(), f.map(Into::into)))
+    .collect();
+    assert_eq!(modules, expected);
+}
+
+#[test]
+fn fullsource_bundle() {
+    let mut sample = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    sample.push("resources");
+    sample.push("bundle");
+
+    let src = syn_file_expand::read_bundle(
+        sample.join("app").join("main.rs"),
+        sample.join("mylib").join("lib.rs"),
+        "mylib",
+        |_| Ok(false),
+        false,
+    )
+    .unwrap();
+
+    let expected: syn::File = syn::parse2(q! {
+        #[macro_use]
+        #[allow(dead_code, unused_imports, unused_macros)]
+        mod mylib {
+            #![allow(clippy::all)]
+            pub use crate::{quad};
+            pub mod util {
+                use crate::mylib::double;
+                pub struct Wrapper(pub u32);
+                pub fn add(a: u32, b: u32) -> u32 {
+                    a + b
+                }
+                pub fn quadruple(x: u32) -> u32 {
+                    double(crate::mylib::quad!(x) / 4) * 2
+                }
+            }
+            pub(crate) fn double(x: u32) -> u32 {
+                crate::mylib::util::add(x, x)
+            }
+            pub use crate::mylib::util::{add, Wrapper};
+            #[macro_export]
+            macro_rules! quad {
+                ($x:expr) => {
+                    $crate::mylib::util::add($crate::mylib::double($x), $crate::mylib::double($x))
+                };
+            }
+        }
+        use crate::mylib as lib;
+        mod cli {
+            use crate::lib::add;
+            pub fn run() -> u32 {
+                add(crate::mylib::quad!(3), format!("{}", crate::mylib::util::add(1, 1)).len() as u32)
+            }
+        }
+        use crate::mylib::util::quadruple;
+        fn main() {
+            let w = crate::mylib::Wrapper(quad!(1));
+            println!("{} {} {}", w.0, quadruple(2), cli::run());
+        }
+    })
+    .unwrap();
+    assert_eq!(
+        src.into_token_stream().to_string(),
+        expected.into_token_stream().to_string()
+    );
+}
+
+#[test]
+fn fullsource_parse_error() {
+    let mut sample = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    sample.push("resources"); 
+    sample.push("broken"); 
+    sample.push("lib.rs"); 
+
+    // Entry points that predate `FailedToParseFile` keep reporting `SynParseError`
+    let ret = syn_file_expand::read_full_crate_source_code(&sample, |_|Ok(false));
+    assert!(matches!(ret, Err(syn_file_expand::Error { inner: syn_file_expand::ErrorCase::SynParseError(_), .. })));
+
+    let ret = syn_file_expand::Expander::new().expand_path(&sample);
+    assert!(matches!(ret, Err(syn_file_expand::Error { inner: syn_file_expand::ErrorCase::FailedToParseFile { .. }, .. })));
+}
@@ -26,6 +26,7 @@ fn trivial() {
 }
 
 #[test]
+#[allow(clippy::cmp_owned)]
 fn simple() {
     let mut before: syn::File = syn::parse2(q! {
         struct Qqq;
@@ -76,6 +77,7 @@ fn simple() {
 }
 
 #[test]
+#[allow(clippy::needless_return)]
 fn nested1() {
     let mut before: syn::File = syn::parse2(q! {
         struct Qqq;
@@ -170,6 +172,7 @@ fn nested1() {
 }
 
 #[test]
+#[allow(clippy::needless_return)]
 fn explicit_paths() {
     let mut before: syn::File = syn::parse2(q! {
         struct Qqq;
@@ -431,3 +434,979 @@ fn cfg3() {
 
     assert_eq!(before, after);
 }
+
+#[test]
+fn unresolved() {
+    let mut before: syn::File = syn::parse2(q! {
+        mod qqq;
+        #[path="www.rs"]
+        mod www;
+    })
+    .unwrap();
+
+    let unresolved = syn_file_expand::expand_modules_into_inline_modules_ex(
+        &mut before,
+        &mut H(|_m, _p| Ok(None), |_cfg| Ok(false)),
+    )
+    .unwrap();
+
+    assert_eq!(unresolved.len(), 2);
+    assert_eq!(unresolved[0].module.to_token_stream().to_string(), "qqq");
+    assert_eq!(
+        unresolved[0].candidates,
+        vec![
+            std::path::PathBuf::from("qqq.rs"),
+            std::path::PathBuf::from("qqq/mod.rs")
+        ]
+    );
+    assert_eq!(unresolved[0].reason, syn_file_expand::UnresolvedReason::Declined);
+    assert_eq!(unresolved[1].module.to_token_stream().to_string(), "www");
+    assert_eq!(unresolved[1].candidates, vec![std::path::PathBuf::from("www.rs")]);
+}
+
+#[test]
+fn unresolved_multimodule() {
+    let expansion = syn_file_expand::Expander::new()
+        .filesystem(|p: &std::path::Path| match p.to_str().unwrap() {
+            "lib.rs" => Ok("mod a; mod missing;".to_owned()),
+            "a.rs" => Ok("struct A;".to_owned()),
+            _ => Err(std::io::ErrorKind::NotFound.into()),
+        })
+        .multimodule(true)
+        .expand_path("lib.rs")
+        .unwrap();
+
+    let after: syn::File = syn::parse2(q! {
+        mod a {
+            struct A;
+        }
+    })
+    .unwrap();
+    assert_eq!(expansion.file, after);
+    // Dropped from the output, so only listed as unresolved
+    assert_eq!(expansion.modules.len(), 1);
+    assert_eq!(expansion.unresolved.len(), 1);
+    assert_eq!(expansion.unresolved[0].reason, syn_file_expand::UnresolvedReason::NotFound);
+}
+
+#[test]
+fn unresolved_strict() {
+    struct StrictResolver;
+    impl syn_file_expand::Resolver for StrictResolver {
+        fn resolve(
+            &mut self,
+            _module_name: syn::Path,
+            _path_relative_to_crate_root: std::path::PathBuf,
+        ) -> Result<Option<syn::File>, syn_file_expand::Error> {
+            Ok(None)
+        }
+
+        fn check_cfg(&mut self, _cfg: syn::Meta) -> Result<bool, syn_file_expand::UserError> {
+            Ok(false)
+        }
+
+        fn error_on_unresolved_modules(&mut self) -> bool {
+            true
+        }
+    }
+
+    let mut before: syn::File = syn::parse2(q! {
+        mod qqq;
+    })
+    .unwrap();
+
+    let ret = syn_file_expand::expand_modules_into_inline_modules(&mut before, &mut StrictResolver);
+
+    assert!(matches!(
+        ret,
+        Err(syn_file_expand::Error {
+            inner: syn_file_expand::ErrorCase::UnresolvedModule {
+                reason: syn_file_expand::UnresolvedReason::Declined,
+                ..
+            },
+            ..
+        })
+    ));
+}
+
+#[test]
+fn check_cfg_not_implemented() {
+    struct NoCfgResolver;
+    impl syn_file_expand::Resolver for NoCfgResolver {
+        fn resolve(
+            &mut self,
+            _module_name: syn::Path,
+            _path_relative_to_crate_root: std::path::PathBuf,
+        ) -> Result<Option<syn::File>, syn_file_expand::Error> {
+            Ok(None)
+        }
+    }
+
+    let mut before: syn::File = syn::parse2(q! {
+        #[cfg(x)]
+        mod qqq;
+    })
+    .unwrap();
+
+    let ret = syn_file_expand::expand_modules_into_inline_modules(&mut before, &mut NoCfgResolver);
+
+    assert!(matches!(
+        ret,
+        Err(syn_file_expand::Error {
+            inner: syn_file_expand::ErrorCase::ErrorFromCallback(_),
+            ..
+        })
+    ));
+}
+
+#[test]
+fn expander_in_memory() {
+    let files = std::collections::HashMap::from([
+        ("src/lib.rs", "mod a; #[cfg(x)] mod b;"),
+        ("src/a.rs", "mod c;"),
+        ("src/a/c.rs", "struct C;"),
+        ("src/b/mod.rs", "struct B;"),
+    ]);
+    let expansion = syn_file_expand::Expander::new()
+        .filesystem(|p: &std::path::Path| {
+            files
+                .get(p.to_str().unwrap())
+                .map(|x| x.to_string())
+                .ok_or_else(|| std::io::ErrorKind::NotFound.into())
+        })
+        .cfg_provider(|cfg| Ok(cfg.path().is_ident("x")))
+        .expand_path("src/lib.rs")
+        .unwrap();
+
+    let after: syn::File = syn::parse2(q! {
+        mod a {
+            mod c {
+                struct C;
+            }
+        }
+        mod b {
+            struct B;
+        }
+    })
+    .unwrap();
+
+    assert_eq!(expansion.file, after);
+    assert_eq!(expansion.modules.len(), 2);
+    assert_eq!(expansion.files.read.len(), 4);
+    assert!(expansion.unresolved.is_empty());
+}
+
+#[test]
+fn raw_identifiers() {
+    let mut before: syn::File = syn::parse2(q! {
+        mod r#type;
+    })
+    .unwrap();
+
+    let unresolved = syn_file_expand::expand_modules_into_inline_modules_ex(
+        &mut before,
+        &mut H(|_m, _p| Ok(None), |_cfg| Ok(false)),
+    )
+    .unwrap();
+
+    assert_eq!(unresolved[0].module.to_token_stream().to_string(), "r#type");
+    assert_eq!(
+        unresolved[0].candidates,
+        vec![
+            std::path::PathBuf::from("type.rs"),
+            std::path::PathBuf::from("type/mod.rs")
+        ]
+    );
+
+    let ret = syn_file_expand::Expander::new()
+        .filesystem(|p: &std::path::Path| match p.to_str().unwrap() {
+            "lib.rs" => Ok("mod r#async;".to_owned()),
+            _ => Err(std::io::ErrorKind::NotFound.into()),
+        })
+        .expand_path("lib.rs");
+    assert_eq!(
+        ret.unwrap_err().to_string(),
+        "Expanding module `r#async`: Cannot open file async.rs: entity not found"
+    );
+}
+
+#[test]
+fn expander_max_depth() {
+    let ret = syn_file_expand::Expander::new()
+        .filesystem(|_: &std::path::Path| Ok(r#"#[path="lib.rs"] mod again;"#.to_owned()))
+        .max_depth(3)
+        .expand_path("lib.rs");
+
+    match ret {
+        Err(syn_file_expand::Error {
+            module,
+            inner: syn_file_expand::ErrorCase::MaxDepthExceeded { max_depth: 3 },
+        }) => assert_eq!(module.segments.len(), 4),
+        _ => panic!("unexpected result"),
+    }
+}
+
+#[test]
+fn cfg_query() {
+    let mut queries = vec![];
+    syn_file_expand::Expander::new()
+        .filesystem(|p: &std::path::Path| {
+            Ok(match p.to_str().unwrap() {
+                "src/lib.rs" => "mod qqq;",
+                "src/qqq.rs" => "#[cfg(a)] mod www; #[cfg_attr(b, path=\"e.rs\")] mod eee;",
+                "src/qqq/eee.rs" => "",
+                _ => return Err(std::io::ErrorKind::NotFound.into()),
+            }
+            .to_owned())
+        })
+        .cfg_query_provider(|query| {
+            queries.push((
+                query.cfg.to_token_stream().to_string(),
+                query.module.to_token_stream().to_string(),
+                query.file,
+                query.origin,
+                query.candidate_path,
+            ));
+            Ok(false)
+        })
+        .expand_path("src/lib.rs")
+        .unwrap();
+
+    use syn_file_expand::CfgOrigin;
+    assert_eq!(
+        queries,
+        vec![
+            ("a".to_owned(), "qqq :: www".to_owned(), Some("qqq.rs".into()), CfgOrigin::Cfg, None),
+            (
+                "b".to_owned(),
+                "qqq :: eee".to_owned(),
+                Some("qqq.rs".into()),
+                CfgOrigin::CfgAttrPath,
+                Some("e.rs".into())
+            ),
+        ]
+    );
+}
+
+#[test]
+fn strip_cfg() {
+    let mut before: syn::File = syn::parse2(q! {
+        #[cfg(no)]
+        fn removed() {}
+        #[cfg(yes)]
+        #[cfg_attr(yes, derive(Debug), cfg_attr(no, derive(Clone)))]
+        #[cfg_attr(no, derive(Copy))]
+        struct S {
+            a: u8,
+            #[cfg(no)]
+            b: u8,
+        }
+        enum E {
+            #[cfg(no)]
+            A,
+            B,
+        }
+        impl S {
+            #[cfg(no)]
+            fn f() {}
+            fn g(x: u8) -> u8 {
+                #[cfg(no)]
+                let y = 1;
+                match x {
+                    #[cfg(no)]
+                    1 => 2,
+                    _ => (x, #[cfg(no)] 5).0,
+                }
+            }
+        }
+        mod m {
+            #[cfg(no)]
+            struct T;
+        }
+    })
+    .unwrap();
+
+    let mut queries = vec![];
+    syn_file_expand::strip_cfg(&mut before, |query| {
+        queries.push((query.module.to_token_stream().to_string(), query.origin));
+        Ok(query.cfg.to_token_stream().to_string() == "yes")
+    })
+    .unwrap();
+
+    let after: syn::File = syn::parse2(q! {
+        #[derive(Debug)]
+        struct S {
+            a: u8,
+        }
+        enum E {
+            B,
+        }
+        impl S {
+            fn g(x: u8) -> u8 {
+                match x {
+                    _ => (x,).0,
+                }
+            }
+        }
+        mod m {
+        }
+    })
+    .unwrap();
+
+    assert_eq!(before, after);
+    assert!(queries.contains(&("m".to_owned(), syn_file_expand::CfgOrigin::ItemCfg)));
+    assert!(queries.contains(&("".to_owned(), syn_file_expand::CfgOrigin::CfgAttr)));
+}
+
+#[test]
+fn normalize_cfg_attr() {
+    let mut before: syn::File = syn::parse2(q! {
+        #[cfg_attr(yes, derive(Debug), cfg_attr(yes, derive(Clone)))]
+        struct S {
+            #[cfg_attr(no, serde(skip))]
+            #[cfg(no)]
+            a: u8,
+        }
+        #[cfg_attr(yes, inline)]
+        fn f(#[cfg_attr(yes, allow(unused))] x: u8) {
+            let _ = #[cfg_attr(yes, allow(unused_parens))] (x);
+        }
+    })
+    .unwrap();
+
+    syn_file_expand::normalize_cfg_attr(&mut before, |query| {
+        Ok(query.cfg.to_token_stream().to_string() == "yes")
+    })
+    .unwrap();
+
+    let after: syn::File = syn::parse2(q! {
+        #[derive(Debug)]
+        #[derive(Clone)]
+        struct S {
+            #[cfg(no)]
+            a: u8,
+        }
+        #[inline]
+        fn f(#[allow(unused)] x: u8) {
+            let _ = #[allow(unused_parens)] (x);
+        }
+    })
+    .unwrap();
+
+    assert_eq!(before, after);
+}
+
+#[test]
+fn multimodule_keeps_cfg() {
+    let expansion = syn_file_expand::Expander::new()
+        .filesystem(|p: &std::path::Path| {
+            Ok(match p.to_str().unwrap() {
+                "src/lib.rs" => "#[cfg(unix)] #[allow(dead_code)] mod a; mod b { struct B; } struct C;",
+                "src/a.rs" => "struct A;",
+                _ => return Err(std::io::ErrorKind::NotFound.into()),
+            }
+            .to_owned())
+        })
+        .cfg_provider(|_| Ok(true))
+        .multimodule(true)
+        .expand_path("src/lib.rs")
+        .unwrap();
+
+    let after: syn::File = syn::parse2(q! {
+        #[cfg(unix)]
+        #[allow(dead_code)]
+        mod a {
+            struct A;
+        }
+        mod b {
+            struct B;
+        }
+        struct C;
+    })
+    .unwrap();
+
+    assert_eq!(expansion.file, after);
+}
+
+#[test]
+fn inner_cfg() {
+    let files = |p: &std::path::Path| {
+        Ok(match p.to_str().unwrap() {
+            "src/lib.rs" => "mod a; mod b;",
+            "src/main.rs" => "mod c;",
+            "src/a.rs" => "#![cfg(x)] struct A;",
+            "src/b.rs" => "#![allow(dead_code)] #![cfg(y)] struct B;",
+            "src/c.rs" => "#![cfg_attr(x, path = \"d.rs\")]",
+            _ => return Err(std::io::ErrorKind::NotFound.into()),
+        }
+        .to_owned())
+    };
+
+    let expansion = syn_file_expand::Expander::new()
+        .filesystem(files)
+        .cfg_provider(|cfg| Ok(cfg.path().is_ident("x")))
+        .expand_path("src/lib.rs")
+        .unwrap();
+    let after: syn::File = syn::parse2(q! {
+        mod a {
+            struct A;
+        }
+        #[cfg(y)]
+        mod b;
+    })
+    .unwrap();
+    assert_eq!(expansion.file, after);
+
+    let expansion = syn_file_expand::Expander::new()
+        .filesystem(files)
+        .cfg_provider(|_| Ok(true))
+        .multimodule(true)
+        .expand_path("src/lib.rs")
+        .unwrap();
+    let after: syn::File = syn::parse2(q! {
+        #[cfg(x)]
+        mod a {
+            struct A;
+        }
+        #[cfg(y)]
+        mod b {
+            #![allow(dead_code)]
+            struct B;
+        }
+    })
+    .unwrap();
+    assert_eq!(expansion.file, after);
+    assert_eq!(expansion.modules[0].cfg.len(), 1);
+
+    let ret = syn_file_expand::Expander::new()
+        .filesystem(files)
+        .expand_path("src/main.rs");
+    assert!(matches!(
+        ret,
+        Err(syn_file_expand::Error {
+            inner: syn_file_expand::ErrorCase::PathAttributeInModuleFile { .. },
+            ..
+        })
+    ));
+}
+
+#[test]
+fn inline_module_cfg_attr() {
+    let files = |p: &std::path::Path| {
+        Ok(match p.to_str().unwrap() {
+            "src/lib.rs" => concat!(
+                "#[cfg_attr(x, allow(a), allow(b))] mod m { struct S; }",
+                "#[cfg_attr(x, allow(a), allow(b))] mod n { mod c; }",
+            ),
+            "src/n/c.rs" => "struct C;",
+            _ => return Err(std::io::ErrorKind::NotFound.into()),
+        }
+        .to_owned())
+    };
+
+    let expansion = syn_file_expand::Expander::new()
+        .filesystem(files)
+        .expand_path("src/lib.rs")
+        .unwrap();
+    let after: syn::File = syn::parse2(q! {
+        #[cfg_attr(x, allow(a), allow(b))]
+        mod m {
+            struct S;
+        }
+        #[cfg_attr(x, allow(a), allow(b))]
+        mod n {
+            mod c {
+                struct C;
+            }
+        }
+    })
+    .unwrap();
+    assert_eq!(expansion.file, after);
+}
+
+#[test]
+fn cfg_predicates_modules() {
+    let ast: syn::File = syn::parse2(q! {
+        #[cfg(a)]
+        mod m {
+            #![cfg(b)]
+            #[cfg_attr(c, cfg(d))]
+            struct S;
+        }
+    })
+    .unwrap();
+
+    let cfgs: Vec<_> = syn_file_expand::cfg_predicates(&ast)
+        .into_iter()
+        .map(|x| {
+            let location = &x.locations[0];
+            (x.predicate, location.module.to_token_stream().to_string(), location.kind)
+        })
+        .collect();
+    use syn_file_expand::CfgKind;
+    assert_eq!(
+        cfgs,
+        vec![
+            ("a".to_owned(), "".to_owned(), CfgKind::Cfg),
+            ("b".to_owned(), "m".to_owned(), CfgKind::Cfg),
+            ("c".to_owned(), "m".to_owned(), CfgKind::CfgAttr),
+            ("d".to_owned(), "m".to_owned(), CfgKind::Cfg),
+        ]
+    );
+}
+
+#[test]
+fn block_modules() {
+    let files = |p: &std::path::Path| {
+        Ok(match p.to_str().unwrap() {
+            "src/lib.rs" => "fn main() { #[path = \"helpers.rs\"] mod helpers; helpers::f(); }",
+            "src/bad.rs" => "impl S { fn g() { mod helpers; } }",
+            "src/helpers.rs" => "pub fn f() {}",
+            _ => return Err(std::io::ErrorKind::NotFound.into()),
+        }
+        .to_owned())
+    };
+
+    let expansion = syn_file_expand::Expander::new()
+        .filesystem(files)
+        .expand_path("src/lib.rs")
+        .unwrap();
+    let after: syn::File = syn::parse2(q! {
+        fn main() {
+            mod helpers {
+                pub fn f() {}
+            }
+            helpers::f();
+        }
+    })
+    .unwrap();
+    assert_eq!(expansion.file, after);
+    assert_eq!(expansion.modules.len(), 1);
+
+    let ret = syn_file_expand::Expander::new()
+        .filesystem(files)
+        .expand_path("src/bad.rs");
+    assert!(matches!(
+        ret,
+        Err(syn_file_expand::Error {
+            inner: syn_file_expand::ErrorCase::ModuleInBlockWithoutPath,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn item_macro_handler() {
+    let expansion = syn_file_expand::Expander::new()
+        .filesystem(|p: &std::path::Path| {
+            Ok(match p.to_str().unwrap() {
+                "src/lib.rs" => "platform_mods! { linux, macos } mod inline { platform_mods! { linux } }",
+                "src/linux.rs" => "struct Linux;",
+                "src/inline/linux.rs" => "struct InlineLinux;",
+                _ => return Err(std::io::ErrorKind::NotFound.into()),
+            }
+            .to_owned())
+        })
+        .cfg_provider(|cfg| Ok(cfg.to_token_stream().to_string() == "target_os = \"linux\""))
+        .item_macro_handler(|_module, item_macro| {
+            if !item_macro.mac.path.is_ident("platform_mods") {
+                return Ok(None);
+            }
+            let names = item_macro
+                .mac
+                .parse_body_with(syn::punctuated::Punctuated::<syn::Ident, syn::Token![,]>::parse_terminated)?;
+            Ok(Some(
+                names
+                    .iter()
+                    .map(|name| {
+                        let os = name.to_string();
+                        syn::parse_quote! { #[cfg(target_os = #os)] mod #name; }
+                    })
+                    .collect(),
+            ))
+        })
+        .expand_path("src/lib.rs")
+        .unwrap();
+
+    let after: syn::File = syn::parse2(q! {
+        mod linux {
+            struct Linux;
+        }
+        #[cfg(target_os = "macos")]
+        mod macos;
+        mod inline {
+            mod linux {
+                struct InlineLinux;
+            }
+        }
+    })
+    .unwrap();
+    assert_eq!(expansion.file, after);
+}
+
+#[test]
+fn item_macro_handler_cfg() {
+    let handler = |_module: syn::Path, item_macro: &syn::ItemMacro| {
+        let names = item_macro
+            .mac
+            .parse_body_with(syn::punctuated::Punctuated::<syn::Ident, syn::Token![,]>::parse_terminated)?;
+        Ok(Some(names.iter().map(|name| syn::parse_quote! { mod #name; }).collect()))
+    };
+    let files = |p: &std::path::Path| {
+        Ok(match p.to_str().unwrap() {
+            "src/lib.rs" => "#[cfg(never)] mods! { a } #[cfg(yes)] mods! { b } #[cfg_attr(yes, cfg(never))] mods! { c }",
+            "src/b.rs" => "struct B;",
+            _ => return Err(std::io::ErrorKind::NotFound.into()),
+        }
+        .to_owned())
+    };
+
+    // Disabled invocations are left as is, without loading their files
+    let expansion = syn_file_expand::Expander::new()
+        .filesystem(files)
+        .cfg_provider(|cfg| Ok(cfg.path().is_ident("yes")))
+        .item_macro_handler(handler)
+        .expand_path("src/lib.rs")
+        .unwrap();
+    let after: syn::File = syn::parse2(q! {
+        #[cfg(never)]
+        mods! { a }
+        mod b {
+            struct B;
+        }
+        #[cfg_attr(yes, cfg(never))]
+        mods! { c }
+    })
+    .unwrap();
+    assert_eq!(expansion.file, after);
+    assert!(!expansion.files.missing.iter().any(|x| x.ends_with("a.rs")));
+
+    // In multimodule mode, gates of the invocation are copied to each produced item
+    let expansion = syn_file_expand::Expander::new()
+        .filesystem(files)
+        .cfg_provider(|cfg| Ok(!cfg.path().is_ident("never")))
+        .multimodule(true)
+        .item_macro_handler(handler)
+        .expand_path("src/lib.rs")
+        .unwrap();
+    let after: syn::File = syn::parse2(q! {
+        #[cfg(never)]
+        mods! { a }
+        #[cfg(yes)]
+        mod b {
+            struct B;
+        }
+        #[cfg_attr(yes, cfg(never))]
+        mods! { c }
+    })
+    .unwrap();
+    assert_eq!(expansion.file, after);
+}
+
+#[test]
+fn evaluate_macro_rules() {
+    let expansion = syn_file_expand::Expander::new()
+        .filesystem(|p: &std::path::Path| {
+            Ok(match p.to_str().unwrap() {
+                "src/lib.rs" => r#"
+                    macro_rules! platform_mods {
+                        ($($vis:vis $name:ident => $path:literal),* $(,)?) => {
+                            $( #[path = $path] $vis mod $name; )*
+                        };
+                    }
+                    platform_mods! { pub linux => "sys/linux.rs", macos => "sys/macos.rs", }
+                    macro_rules! unsupported { ($e:expr) => { mod x; } }
+                    unsupported!(1 + 1);
+                    mod inner { platform_mods! { nested => "nested.rs" } }
+                    #[cfg(never)]
+                    platform_mods! { skipped => "skipped.rs" }
+                    macro_rules! pick { () => { mod picked; } }
+                    #[cfg(never)]
+                    macro_rules! pick { () => { mod wrong; } }
+                    pick!();
+                "#,
+                "src/picked.rs" => "struct Picked;",
+                "src/sys/linux.rs" => "struct Linux;",
+                "src/sys/macos.rs" => "struct Macos;",
+                "src/inner/nested.rs" => "struct Nested;",
+                _ => return Err(std::io::ErrorKind::NotFound.into()),
+            }
+            .to_owned())
+        })
+        .evaluate_macro_rules(true)
+        .expand_path("src/lib.rs")
+        .unwrap();
+
+    let after: syn::File = syn::parse2(q! {
+        pub mod linux {
+            struct Linux;
+        }
+        mod macos {
+            struct Macos;
+        }
+        mod inner {
+            mod nested {
+                struct Nested;
+            }
+        }
+        mod picked {
+            struct Picked;
+        }
+    })
+    .unwrap();
+    let (macros, modules): (Vec<_>, Vec<_>) = expansion
+        .file
+        .items
+        .into_iter()
+        .partition(|x| matches!(x, syn::Item::Macro(..)));
+    assert_eq!(modules, after.items);
+    // definitions of all macros, the unsupported invocation and the disabled one are kept
+    assert_eq!(macros.len(), 6);
+    assert!(!expansion.files.missing.iter().any(|x| x.ends_with("skipped.rs")));
+    assert_eq!(expansion.unexpanded_macros.len(), 1);
+    assert_eq!(expansion.unexpanded_macros[0].name, "unsupported");
+    assert_eq!(
+        expansion.unexpanded_macros[0].reason,
+        syn_file_expand::UnexpandedMacroReason::UnsupportedDefinition
+    );
+}
+
+#[test]
+fn macro_rules_textual_scope() {
+    let expansion = syn_file_expand::Expander::new()
+        .filesystem(|p: &std::path::Path| {
+            Ok(match p.to_str().unwrap() {
+                "src/lib.rs" => r#"
+                    macro_rules! pick { () => { mod first; } }
+                    mod early;
+                    mod inline { pick!(); late!(); }
+                    mod later;
+                    macro_rules! late { () => { mod z; } }
+                    macro_rules! pick { () => { mod second; } }
+                    pick!();
+                "#,
+                "src/early.rs" => "pick!();",
+                "src/later.rs" => "late!();",
+                "src/early/first.rs" => "struct First;",
+                "src/inline/first.rs" => "struct InlineFirst;",
+                "src/second.rs" => "struct Second;",
+                _ => return Err(std::io::ErrorKind::NotFound.into()),
+            }
+            .to_owned())
+        })
+        .evaluate_macro_rules(true)
+        .expand_path("src/lib.rs")
+        .unwrap();
+
+    let modules: Vec<_> = expansion.modules.iter().map(|x| x.name.to_string()).collect();
+    assert_eq!(modules, ["early", "inline", "later", "second"]);
+    assert_eq!(expansion.modules[0].children[0].name, "first");
+    assert_eq!(expansion.modules[1].children[0].name, "first");
+    // `late` is defined after `mod later;`, so it is not in scope there
+    assert!(expansion.modules[2].children.is_empty());
+    assert!(!expansion.files.missing.iter().any(|x| x.ends_with("z.rs")));
+}
+
+#[test]
+fn tolerant_parsing() {
+    let files = |p: &std::path::Path| {
+        Ok(match p.to_str().unwrap() {
+            "src/lib.rs" => "mod a; mod b;",
+            "src/broken.rs" => "mod c;",
+            "src/a.rs" => "struct A = 5;",
+            "src/b.rs" => "struct B;",
+            "src/c.rs" => "fn f() {",
+            _ => return Err(std::io::ErrorKind::NotFound.into()),
+        }
+        .to_owned())
+    };
+
+    let expansion = syn_file_expand::Expander::new()
+        .filesystem(files)
+        .tolerant_parsing(true)
+        .expand_path("src/lib.rs")
+        .unwrap();
+    assert_eq!(
+        expansion.file.to_token_stream().to_string(),
+        "mod a { struct A = 5 ; } mod b { struct B ; }"
+    );
+    assert_eq!(expansion.unparsed_files.len(), 1);
+    assert_eq!(expansion.unparsed_files[0].path, std::path::Path::new("src/a.rs"));
+
+    // Not even tokenizable
+    let ret = syn_file_expand::Expander::new()
+        .filesystem(files)
+        .tolerant_parsing(true)
+        .expand_path("src/broken.rs");
+    assert!(matches!(
+        ret,
+        Err(syn_file_expand::Error {
+            inner: syn_file_expand::ErrorCase::FailedToParseFile { .. },
+            ..
+        })
+    ));
+}
+
+#[test]
+fn resolve_source() {
+    struct TextResolver;
+    impl syn_file_expand::Resolver for TextResolver {
+        fn resolve(
+            &mut self,
+            module_name: syn::Path,
+            path: std::path::PathBuf,
+        ) -> Result<Option<syn::File>, syn_file_expand::Error> {
+            self.resolve_source(module_name.clone(), path.clone())?
+                .map(|x| x.parse(module_name, &path))
+                .transpose()
+        }
+
+        fn resolve_source(
+            &mut self,
+            _module_name: syn::Path,
+            path: std::path::PathBuf,
+        ) -> Result<Option<syn_file_expand::ModuleSource>, syn_file_expand::Error> {
+            Ok(match path.to_str().unwrap() {
+                "a.rs" => Some("struct A;\nmod b;".to_owned().into()),
+                "a/b.rs" => Some(q! { struct B; }.into()),
+                "broken.rs" => Some("struct C;\nstruct D =;".to_owned().into()),
+                _ => None,
+            })
+        }
+    }
+
+    let mut before: syn::File = syn::parse2(q! {
+        mod a;
+    })
+    .unwrap();
+    syn_file_expand::expand_modules_into_inline_modules(&mut before, &mut TextResolver).unwrap();
+    let after: syn::File = syn::parse2(q! {
+        mod a {
+            struct A;
+            mod b {
+                struct B;
+            }
+        }
+    })
+    .unwrap();
+    assert_eq!(before, after);
+
+    let mut before: syn::File = syn::parse2(q! {
+        mod broken;
+    })
+    .unwrap();
+    let ret = syn_file_expand::expand_modules_into_inline_modules(&mut before, &mut TextResolver);
+    match ret {
+        Err(syn_file_expand::Error {
+            inner: syn_file_expand::ErrorCase::FailedToParseFile { path, line, column, .. },
+            ..
+        }) if cfg!(feature = "span-locations") => assert_eq!((path.to_str().unwrap(), line, column), ("broken.rs", 2, 10)),
+        Err(syn_file_expand::Error {
+            inner: syn_file_expand::ErrorCase::FailedToParseFile { path, line, column, .. },
+            ..
+        }) => assert_eq!((path.to_str().unwrap(), line, column), ("broken.rs", 0, 0)),
+        _ => panic!("unexpected result"),
+    }
+}
+
+#[test]
+fn merge_identical_modules() {
+    let expansion = syn_file_expand::Expander::new()
+        .filesystem(|p: &std::path::Path| {
+            Ok(match p.to_str().unwrap() {
+                "src/lib.rs" => r#"
+                    #[cfg_attr(unix, path = "stub.rs")]
+                    #[cfg_attr(windows, path = "stub_copy.rs")]
+                    mod sys;
+                "#,
+                "src/stub.rs" | "src/stub_copy.rs" => "fn f() {}",
+                "src/sys.rs" => "fn g() {}",
+                _ => return Err(std::io::ErrorKind::NotFound.into()),
+            }
+            .to_owned())
+        })
+        .cfg_provider(|_| Ok(true))
+        .multimodule(true)
+        .merge_identical_modules(true)
+        .expand_path("src/lib.rs")
+        .unwrap();
+
+    let after: syn::File = syn::parse2(q! {
+        #[cfg(any(unix, windows))]
+        mod sys {
+            fn f() {}
+        }
+        #[cfg(not(any(unix, windows)))]
+        mod sys {
+            fn g() {}
+        }
+    })
+    .unwrap();
+
+    assert_eq!(expansion.file, after);
+    assert_eq!(expansion.modules.len(), 3);
+}
+
+#[cfg(unix)]
+#[test]
+fn absolute_path() {
+    let expansion = syn_file_expand::Expander::new()
+        .filesystem(|p: &std::path::Path| {
+            Ok(match p.to_str().unwrap() {
+                "src/lib.rs" => r#"#[path = "/abs/x.rs"] mod x;"#,
+                "/abs/x.rs" => "mod y;",
+                "/abs/y.rs" => "struct Y;",
+                _ => return Err(std::io::ErrorKind::NotFound.into()),
+            }
+            .to_owned())
+        })
+        .expand_path("src/lib.rs")
+        .unwrap();
+
+    assert_eq!(expansion.modules[0].file, Some("/abs/x.rs".into()));
+    assert_eq!(expansion.modules[0].children[0].file, Some("/abs/y.rs".into()));
+}
+
+#[test]
+#[cfg(feature = "span-locations")]
+fn expand_path_to_text() {
+    let files = |p: &std::path::Path| {
+        Ok(match p.to_str().unwrap() {
+            "src/lib.rs" => "\u{feff}//! Crate docs\n\n/// Network\npub mod net; // trailing comment\n\nfn main() {\n    #[path = \"util.rs\"]\n    mod helpers;\n}\n\n#[cfg(unix)] mod sys;\n",
+            "src/net.rs" => "// TCP\nmod tcp;\n\nconst S: &str = \"multi\nline\";\n",
+            "src/net/tcp.rs" => "pub struct Stream;\n",
+            "src/util.rs" => "\n",
+            _ => return Err(std::io::ErrorKind::NotFound.into()),
+        }
+        .to_owned())
+    };
+
+    let expansion = syn_file_expand::Expander::new()
+        .filesystem(files)
+        .expand_path_to_text("src/lib.rs")
+        .unwrap();
+    assert_eq!(
+        expansion.text,
+        r#"//! Crate docs
+
+/// Network
+pub mod net {
+    // TCP
+    mod tcp {
+        pub struct Stream;
+    }
+
+    const S: &str = "multi
+line";
+} // trailing comment
+
+fn main() {
+    mod helpers {}
+}
+
+#[cfg(unix)] mod sys;
+"#
+    );
+    assert_eq!(expansion.files.read.len(), 4);
+    assert_eq!(expansion.modules.len(), 2);
+}